@@ -0,0 +1,24 @@
+-- Points downsampled into fixed size buckets, so long ranges don't scan every raw point.
+-- `time` is the start of the bucket and `resolution` its length in seconds.
+CREATE TABLE IF NOT EXISTS rollup (
+    series_id INTEGER references series(id) not null,
+    resolution INTEGER not null,
+    time INTEGER not null,
+    count INTEGER not null,
+    sum REAL not null,
+    min REAL not null,
+    max REAL not null,
+    first_time INTEGER not null,
+    first REAL not null,
+    last_time INTEGER not null,
+    last REAL not null,
+    PRIMARY KEY (series_id, resolution, time)
+);
+
+-- Points with a time before `rolled_until` have been rolled up, as have late points with an id up
+-- to `last_point_id`.
+CREATE TABLE IF NOT EXISTS rollup_state (
+    resolution INTEGER PRIMARY KEY,
+    rolled_until INTEGER not null,
+    last_point_id INTEGER not null
+);
@@ -0,0 +1,4 @@
+-- Times are unix milliseconds instead of seconds. Rollup resolutions stay in seconds.
+UPDATE point SET time = time * 1000;
+UPDATE rollup SET time = time * 1000, first_time = first_time * 1000, last_time = last_time * 1000;
+UPDATE rollup_state SET rolled_until = rolled_until * 1000;
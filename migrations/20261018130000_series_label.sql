@@ -0,0 +1,10 @@
+-- The labels of series whose names have them, e.g. `mppt` = `a` for `panel_power_w{mppt="a"}`, so
+-- that selectors can find series by label without parsing every name.
+CREATE TABLE IF NOT EXISTS series_label (
+    series_id INTEGER NOT NULL references series(id),
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (series_id, key)
+);
+
+CREATE INDEX IF NOT EXISTS idx_series_label_key_value ON series_label(key, value);
@@ -0,0 +1,24 @@
+CREATE TABLE IF NOT EXISTS series (
+    id BIGSERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE
+);
+
+-- Times are unix seconds, as in the SQLite schema. The id orders points with the same time and
+-- isn't a primary key as TimescaleDB requires unique keys to include the time.
+CREATE TABLE IF NOT EXISTS point (
+    id BIGSERIAL NOT NULL,
+    series_id BIGINT NOT NULL REFERENCES series(id),
+    time BIGINT NOT NULL,
+    value REAL NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_point_series_time ON point(series_id, time);
+
+-- On TimescaleDB partition points into weekly chunks
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'timescaledb') THEN
+        PERFORM create_hypertable('point', 'time', chunk_time_interval => 604800, if_not_exists => TRUE, migrate_data => TRUE);
+    END IF;
+END
+$$;
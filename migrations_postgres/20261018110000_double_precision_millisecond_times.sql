@@ -0,0 +1,29 @@
+-- Values are double precision and times unix milliseconds instead of seconds. The points are
+-- copied into a new table rather than updated in place, as TimescaleDB doesn't move updated rows
+-- between chunks.
+ALTER TABLE point RENAME TO point_seconds;
+ALTER INDEX idx_point_series_time RENAME TO idx_point_seconds_series_time;
+
+CREATE TABLE point (
+    id BIGINT NOT NULL DEFAULT nextval('point_id_seq'),
+    series_id BIGINT NOT NULL REFERENCES series(id),
+    time BIGINT NOT NULL,
+    value DOUBLE PRECISION NOT NULL
+);
+ALTER SEQUENCE point_id_seq OWNED BY point.id;
+
+CREATE INDEX idx_point_series_time ON point(series_id, time);
+
+-- On TimescaleDB partition points into weekly chunks
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'timescaledb') THEN
+        PERFORM create_hypertable('point', 'time', chunk_time_interval => 604800000, if_not_exists => TRUE);
+    END IF;
+END
+$$;
+
+INSERT INTO point (id, series_id, time, value)
+SELECT id, series_id, time * 1000, value FROM point_seconds;
+
+DROP TABLE point_seconds;
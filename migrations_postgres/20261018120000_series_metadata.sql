@@ -0,0 +1,10 @@
+-- How the values of a series are displayed. `decimals` is the number of decimal places and `min`
+-- and `max` the range the values are expected to stay in, used by gauges and line axes.
+CREATE TABLE IF NOT EXISTS series_metadata (
+    series_id BIGINT PRIMARY KEY REFERENCES series(id),
+    unit TEXT,
+    description TEXT,
+    decimals BIGINT,
+    min DOUBLE PRECISION,
+    max DOUBLE PRECISION
+);
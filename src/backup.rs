@@ -0,0 +1,163 @@
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::path::{Component, Path, PathBuf};
+use anyhow::anyhow;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use crate::env::{Collectors, Dashboards, Environment, Settings, Widget};
+use crate::storage::SqliteStorage;
+
+const DATABASE: &str = "slapdash.db";
+const CONFIG: &str = "config.txt";
+const COLLECTORS: &str = "collectors.xml";
+const DASHBOARDS: &str = "dashboards";
+/// Where an archive is unpacked and checked before it replaces the current files
+const RESTORE_DIR: &str = "restore.tmp";
+/// Where the files replaced by a restore are kept
+const REPLACED_DIR: &str = "before-restore";
+
+/// Writes the database, `config.txt`, `collectors.xml` and the dashboards to a gzipped tar archive.
+/// The database is copied with `VACUUM INTO` so the server can keep running during a backup.
+pub(crate) async fn backup(env: &Environment, filename: &Path) -> anyhow::Result<()> {
+    let snapshot = env.dir.join("backup.tmp.db");
+    let mut partial = OsString::from(filename);
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+
+    let _ = fs::remove_file(&snapshot);
+    let result = write_archive(env, &snapshot, &partial).await;
+    let _ = fs::remove_file(&snapshot);
+    if let Err(e) = result {
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+
+    // The previous backup is only replaced once the new one is complete
+    fs::rename(&partial, filename)?;
+    println!("Backed up to {}", filename.display());
+    Ok(())
+}
+
+async fn write_archive(env: &Environment, snapshot: &Path, filename: &Path) -> anyhow::Result<()> {
+    let file = File::create(filename)?;
+    let mut archive = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+    env.db.backup(snapshot).await?;
+    archive.append_path_with_name(snapshot, DATABASE)?;
+    archive.append_path_with_name(Settings::path(&env.dir), CONFIG)?;
+    archive.append_path_with_name(Collectors::path(&env.dir), COLLECTORS)?;
+    let mut dashboards = fs::read_dir(Dashboards::path(&env.dir))?.collect::<Result<Vec<_>, _>>()?;
+    dashboards.sort_by_key(|entry| entry.file_name());
+    for entry in dashboards {
+        archive.append_path_with_name(entry.path(), Path::new(DASHBOARDS).join(entry.file_name()))?;
+    }
+
+    archive.into_inner()?.finish()?.sync_all()?;
+    Ok(())
+}
+
+/// Replaces the current files with those of a backup, after checking that the backup is readable
+/// and migrating its database. The replaced files are kept in `before-restore` in the data directory.
+pub(crate) async fn restore(dir: &Path, filename: &Path) -> anyhow::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    // A running server would keep using the replaced database. Holding the lock also keeps a
+    // server from starting until the restore is done.
+    let _lock = Environment::lock(dir)?.ok_or(anyhow!("A server is running with {}. Stop it before restoring", dir.display()))?;
+
+    let staging = dir.join(RESTORE_DIR);
+    let _ = fs::remove_dir_all(&staging);
+    fs::create_dir_all(staging.join(DASHBOARDS))?;
+    if let Err(e) = unpack(filename, &staging).await {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(e);
+    }
+
+    let replaced = dir.join(REPLACED_DIR);
+    let _ = fs::remove_dir_all(&replaced);
+    fs::create_dir_all(&replaced)?;
+    for name in [DATABASE, CONFIG, COLLECTORS, DASHBOARDS] {
+        if !staging.join(name).exists() {
+            continue;
+        }
+        let mut names = vec![name.to_string()];
+        if name == DATABASE {
+            names.extend([format!("{DATABASE}-wal"), format!("{DATABASE}-shm"), format!("{DATABASE}-journal")]);
+        }
+        for name in names.iter().filter(|name| dir.join(name).exists()) {
+            fs::rename(dir.join(name), replaced.join(name))?;
+        }
+        fs::rename(staging.join(name), dir.join(name))?;
+    }
+    fs::remove_dir_all(&staging)?;
+
+    println!("Restored {}. The replaced files were moved to {}", filename.display(), replaced.display());
+    Ok(())
+}
+
+/// Unpacks an archive into `staging` and checks its files
+async fn unpack(filename: &Path, staging: &Path) -> anyhow::Result<()> {
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(filename)?));
+    for entry in archive.entries().map_err(|e| anyhow!("Not a backup archive: {e}"))? {
+        let mut entry = entry.map_err(|e| anyhow!("Not a backup archive: {e}"))?;
+        let path = entry.path()?.into_owned();
+        if !is_backup_file(&path) {
+            return Err(anyhow!("Unexpected file in backup: {}", path.display()));
+        }
+        entry.unpack(staging.join(&path))?;
+    }
+
+    let config = fs::read_to_string(staging.join(CONFIG))
+        .map_err(|_| anyhow!("Not a backup archive: {CONFIG} is missing"))?;
+    serde_ini::from_str::<Settings>(&config).map_err(|e| anyhow!("Invalid {CONFIG} in backup: {e}"))?;
+
+    if let Ok(collectors) = fs::read_to_string(staging.join(COLLECTORS)) {
+        quick_xml::de::from_str::<Collectors>(&collectors).map_err(|e| anyhow!("Invalid {COLLECTORS} in backup: {e}"))?;
+    }
+
+    for entry in fs::read_dir(staging.join(DASHBOARDS))? {
+        let entry = entry?;
+        let dashboard = fs::read_to_string(entry.path())?;
+        quick_xml::de::from_str::<Widget>(&dashboard)
+            .map_err(|e| anyhow!("Invalid dashboard {} in backup: {e}", entry.file_name().to_string_lossy()))?;
+    }
+
+    if staging.join(DATABASE).exists() {
+        let url = format!("sqlite://{}", staging.join(DATABASE).display());
+        let db = SqliteStorage::connect(&url)
+            .await
+            .map_err(|e| anyhow!("Failed to open the database in the backup: {e}"))?;
+        let result = db.check_integrity().await;
+        db.close().await;
+        result?;
+    }
+
+    Ok(())
+}
+
+/// Whether an archive entry is one of the files written by `backup`, so that a crafted archive
+/// can't write elsewhere
+fn is_backup_file(path: &Path) -> bool {
+    let components: Vec<_> = path.components().collect();
+    match components.as_slice() {
+        [Component::Normal(name)] => [DATABASE, CONFIG, COLLECTORS].iter().any(|file| name == file),
+        [Component::Normal(dir), Component::Normal(_)] => dir == &DASHBOARDS,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_backup_file() {
+        assert!(is_backup_file(Path::new("slapdash.db")));
+        assert!(is_backup_file(Path::new("dashboards/default.xml")));
+        assert!(!is_backup_file(Path::new("dashboards")));
+        assert!(!is_backup_file(Path::new("dashboards/../config.txt")));
+        assert!(!is_backup_file(Path::new("/etc/passwd")));
+        assert!(!is_backup_file(Path::new("../.bashrc")));
+    }
+}
@@ -1,17 +1,32 @@
+use chrono::{DateTime, NaiveDateTime};
 use clap::{Parser, Subcommand};
 use std::{net::SocketAddr, path::PathBuf};
 use regex::Regex;
+use crate::export::ExportFormat;
+use crate::labels::{validate_label, validate_series};
 
 /// Validates that a string is a valid socket address
-fn validate_socket_addr(addr: &str) -> Result<String, String> {
+fn validate_socket_addr(addr: &str) -> Result<SocketAddr, String> {
     addr.parse::<SocketAddr>()
-        .map(|_| ())
-        .map_err(|e| format!("Invalid socket address '{addr}': {e}"))?;
-    Ok(addr.to_string())
+        .map_err(|e| format!("Invalid socket address '{addr}': {e}"))
+}
+
+/// Parses a time given as unix seconds, which may have a fractional part, or RFC3339
+pub(crate) fn parse_time(time: &str) -> Result<NaiveDateTime, String> {
+    if let Ok(seconds) = time.parse::<f64>() {
+        return Some(seconds * 1000.0)
+            .filter(|millis| millis.is_finite())
+            .and_then(|millis| DateTime::from_timestamp_millis(millis.round() as i64))
+            .map(|t| t.naive_utc())
+            .ok_or(format!("Time is out of range: {time}"));
+    }
+    DateTime::parse_from_rfc3339(time)
+        .map(|t| t.naive_utc())
+        .map_err(|_| "Time must be unix seconds or RFC3339, e.g. 2024-06-13T15:30:00Z".to_string())
 }
 
 /// Validates that a string contains only lowercase letters, underscores, and hyphens
-fn validate_name(name: &str) -> Result<String, String> {
+pub(crate) fn validate_name(name: &str) -> Result<String, String> {
     if name.is_empty() {
         return Err("Cannot be empty".to_string());
     }
@@ -23,11 +38,21 @@ fn validate_name(name: &str) -> Result<String, String> {
     Ok(name.to_string())
 }
 
+/// Parses a label given as key=value
+fn parse_label(text: &str) -> Result<(String, String), String> {
+    let (key, value) = text.split_once('=').ok_or("Labels must be given as key=value".to_string())?;
+    validate_label(key, value)?;
+    Ok((key.to_string(), value.to_string()))
+}
+
 #[derive(Parser)]
 #[command(name = "slapdash")]
 #[command(about = "A dashboard and monitoring tool")]
 #[command(version)]
 pub struct Cli {
+    /// Directory holding the configuration, dashboards and database. Defaults to ~/.slapdash
+    #[arg(long, global = true, env = "SLAPDASH_DIR")]
+    pub data_dir: Option<PathBuf>,
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -50,20 +75,33 @@ pub enum Commands {
         command: DashboardCommands,
     },
 
+    /// Series management commands
+    Series {
+        #[command(subcommand)]
+        command: SeriesCommands,
+    },
+
     /// Push a data point to the dashboard
     Push{
-        /// The name of the series that the data point belongs to
-        #[arg(value_parser = validate_name)]
+        /// The name of the series that the data point belongs to, optionally with labels such as
+        /// panel_power_w{mppt=a}
+        #[arg(value_parser = validate_series)]
         series: String,
         /// The data point, a number
-        value: f32
+        value: f64,
+        /// The time of the data point, as unix seconds or RFC3339. Defaults to now
+        #[arg(short, long)]
+        time: Option<String>,
+        /// A label of the series as key=value. May be given more than once
+        #[arg(short, long = "label", value_parser = parse_label)]
+        labels: Vec<(String, String)>,
     },
 
     /// Push multiple data points to the dashboard from a CSV file.
     #[command(
         long_about = "Push multiple data points to the dashboard from a CSV file.\n\
 The CSV file should contain columns: series, time, and value. Each row represents a data point to be pushed. There should be no header row. \
-The 'series' column specifies the series name, 'time' is the timestamp and must be formatted YYYY-MM-dd HH:mm:ss, and 'value' is the data point value and must parse as an f32. \
+The 'series' column specifies the series name, quoted if it has labels, 'time' is the timestamp and must be formatted YYYY-MM-dd HH:mm:ss with optional milliseconds (HH:mm:ss.SSS), and 'value' is the data point value and must parse as an f64. \
 This is an example row:\n\n
 my_example_series,2024-06-13 15:30:00,32.14
 "
@@ -73,6 +111,39 @@ my_example_series,2024-06-13 15:30:00,32.14
         filename: PathBuf
     },
 
+    /// Export the points of one or more series
+    Export {
+        /// Names of the series, or globs such as host_*
+        #[arg(required = true)]
+        series: Vec<String>,
+        /// Export points at or after this time, as unix seconds or RFC3339
+        #[arg(long, value_parser = parse_time)]
+        from: Option<NaiveDateTime>,
+        /// Export points before this time, as unix seconds or RFC3339
+        #[arg(long, value_parser = parse_time)]
+        to: Option<NaiveDateTime>,
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t)]
+        format: ExportFormat,
+        /// File to write to. Defaults to stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Back up the database, config.txt, collectors.xml and the dashboards to a .tar.gz file. The
+    /// server may keep running
+    Backup {
+        /// Archive to write, e.g. slapdash-backup.tar.gz
+        filename: PathBuf,
+    },
+
+    /// Restore a backup made with `slapdash backup`, replacing the current database and
+    /// configuration. Stop the server first
+    Restore {
+        /// Archive written by `slapdash backup`
+        filename: PathBuf,
+    },
+
     /// List the dashboards
     List,
 }
@@ -86,3 +157,77 @@ pub enum DashboardCommands {
         name: String,
     },
 }
+
+#[derive(Subcommand)]
+pub enum SeriesCommands {
+    /// List the series with their number of points and first and last times
+    List,
+    /// Show the number of points, first and last times and metadata of a series
+    Inspect {
+        /// Name of the series
+        name: String,
+    },
+    /// Rename a series
+    Rename {
+        /// Current name of the series
+        from: String,
+        /// New name of the series (lowercase letters, underscores, and hyphens only), optionally with labels
+        #[arg(value_parser = validate_series)]
+        to: String,
+    },
+    /// Delete a series, or only its points between --from and --to
+    Delete {
+        /// Name of the series
+        name: String,
+        /// Delete points at or after this time, as unix seconds or RFC3339
+        #[arg(long, value_parser = parse_time)]
+        from: Option<NaiveDateTime>,
+        /// Delete points before this time, as unix seconds or RFC3339
+        #[arg(long, value_parser = parse_time)]
+        to: Option<NaiveDateTime>,
+    },
+    /// Move the points of one series into another and delete the first
+    Merge {
+        /// Series whose points are moved. It is deleted afterwards
+        from: String,
+        /// Series that receives the points. It is created if it doesn't exist
+        #[arg(value_parser = validate_series)]
+        into: String,
+    },
+    /// Set the unit, description, decimal places or expected range that widgets display a series
+    /// with. Options which aren't given keep their current value, an empty unit or description
+    /// clears it
+    Set {
+        /// Name of the series. It is created if it doesn't exist
+        #[arg(value_parser = validate_series)]
+        name: String,
+        /// Unit shown after values, e.g. kWh or %
+        #[arg(long)]
+        unit: Option<String>,
+        /// What the series measures
+        #[arg(long)]
+        description: Option<String>,
+        /// Number of decimal places shown
+        #[arg(long)]
+        decimals: Option<i64>,
+        /// Lower end of the range the values are expected to stay in
+        #[arg(long, allow_negative_numbers = true)]
+        min: Option<f64>,
+        /// Upper end of the range the values are expected to stay in
+        #[arg(long, allow_negative_numbers = true)]
+        max: Option<f64>,
+        /// Clear the given fields, e.g. --clear=min,max, or all of them, before setting the given options
+        #[arg(long, value_enum, num_args = 0.., value_delimiter = ',', require_equals = true)]
+        clear: Option<Vec<MetadataField>>,
+    },
+}
+
+/// A field of a series' metadata which `series set --clear` can clear
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum MetadataField {
+    Unit,
+    Description,
+    Decimals,
+    Min,
+    Max,
+}
@@ -1,19 +1,39 @@
+use std::collections::BTreeMap;
+use std::io::Read;
 use std::num::FpCategory;
-use sqlx::SqliteConnection;
-use crate::db;
+use crate::{db, export, influx, labels, webhook};
+use crate::expr::Expr;
+use crate::labels::Selector;
+use crate::storage::Storage;
 use axum::extract::{Path, Query, State};
-use crate::{model::Dashboard, view::MainTemplate};
-use axum::http::StatusCode;
+use crate::{model::{Aggregation, Dashboard, SeriesMetadata, TimeRange}, view::MainTemplate};
+use axum::body::{Body, Bytes};
+use axum::http::{header, HeaderMap, StatusCode};
 use askama::Template;
-use axum::response::Html;
+use axum::response::{Html, IntoResponse, Response};
+use axum::Json;
+use chrono::{NaiveDateTime, SecondsFormat, SubsecRound, TimeDelta};
+use crate::cli::parse_time;
+use crate::export::{ExportFormat, Exporter, ResolveError, SharedBuffer};
 use crate::env::Environment;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use base64::prelude::*;
+use flate2::read::GzDecoder;
+use tokio_stream::wrappers::ReceiverStream;
 
 const DEFAULT_RANGE_SECONDS: u32 = 86400;
+const MAX_CLOCK_SKEW_SECONDS: i64 = 300;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 pub(crate) struct DashboardQuery {
+    /// Length of the range in seconds
     range: Option<u32>,
+    /// Start of the range, as unix seconds or RFC3339
+    from: Option<String>,
+    /// End of the range, as unix seconds or RFC3339. Without `from` or `to` the range ends now.
+    to: Option<String>,
+    /// Seconds to move the range back by
+    offset: Option<u32>,
 }
 
 pub(crate) async fn get_default (
@@ -21,7 +41,7 @@ pub(crate) async fn get_default (
     State(env): State<Environment>,
 ) -> Result<Html<String>, StatusCode>
 {
-    let range = resolve_range(query.range)?;
+    let range = resolve_range(&query, chrono::Utc::now().naive_utc()).map_err(|_| StatusCode::BAD_REQUEST)?;
     _get("default", &env, range).await
 }
 
@@ -31,32 +51,39 @@ pub(crate) async fn get (
     State(env): State<Environment>,
 ) -> Result<Html<String>, StatusCode>
 {
-    let range = resolve_range(query.range)?;
+    let range = resolve_range(&query, chrono::Utc::now().naive_utc()).map_err(|_| StatusCode::BAD_REQUEST)?;
     _get(&dashboard, &env, range).await
 }
 
-fn resolve_range(range: Option<u32>) -> Result<u32, StatusCode> {
-    match range {
-        None => Ok(DEFAULT_RANGE_SECONDS),
-        Some(r) if r > 0 => Ok(r),
-        Some(_) => Err(StatusCode::BAD_REQUEST),
+/// Works out the range a dashboard shows from `from` and `to`, or either with `range`, and then
+/// moves it back by `offset`
+fn resolve_range(query: &DashboardQuery, now: NaiveDateTime) -> Result<TimeRange, String> {
+    let parse = |time: &Option<String>| time.as_deref().map(parse_time).transpose();
+    let seconds_between = |from: NaiveDateTime, to: NaiveDateTime| {
+        u32::try_from((to - from).num_seconds()).ok().filter(|s| *s > 0).ok_or("The range must end after it starts".to_string())
+    };
+    if query.range == Some(0) {
+        return Err("The range must be more than 0 seconds".to_string());
     }
-}
 
-async fn _get(dashboard_name: &str, env: &Environment, range_seconds: u32) -> Result<Html<String>, StatusCode> {
-    let mut db = env
-        .db
-        .acquire()
-        .await
-        .map_err(|e| {
-            println!("Error while acquiring database connection: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+    let mut range = match (parse(&query.from)?, parse(&query.to)?, query.range) {
+        (Some(_), Some(_), Some(_)) => return Err("Give at most two of from, to and range".to_string()),
+        (Some(from), Some(to), None) => TimeRange{ end: Some(to), seconds: seconds_between(from, to)? },
+        (Some(from), None, Some(seconds)) => TimeRange{ end: Some(from + TimeDelta::seconds(seconds as i64)), seconds },
+        (Some(from), None, None) => TimeRange::last(seconds_between(from, now)?),
+        (None, to, seconds) => TimeRange{ end: to, seconds: seconds.unwrap_or(DEFAULT_RANGE_SECONDS) },
+    };
+    if let Some(offset) = query.offset.filter(|offset| *offset > 0) {
+        range.end = Some(range.end.unwrap_or(now) - TimeDelta::seconds(offset as i64));
+    }
+    Ok(range)
+}
 
+async fn _get(dashboard_name: &str, env: &Environment, range: TimeRange) -> Result<Html<String>, StatusCode> {
     let dashboard = env.dashboards.get(dashboard_name)
         .ok_or(StatusCode::NOT_FOUND)?;
 
-    let template = build_main(dashboard, &mut db, range_seconds)
+    let template = build_main(dashboard, &*env.db, range)
         .await
         .map_err(|e| {
             println!("Error while building template: {}", e);
@@ -73,23 +100,37 @@ async fn _get(dashboard_name: &str, env: &Environment, range_seconds: u32) -> Re
     Ok(Html(html))
 }
 
+#[derive(Deserialize)]
+pub(crate) struct PutQuery {
+    time: Option<String>,
+}
+
 pub(crate) async fn put(
-    Path((secret, series, value)): Path<(String, String, f32)>, 
+    Path((secret, series, value)): Path<(String, String, f64)>, 
+    Query(query): Query<PutQuery>,
     State(env): State<Environment>,
-) -> Result<String, StatusCode> {
+) -> Result<String, Response> {
     if secret != env.settings.secret {
-        return Err(StatusCode::UNAUTHORIZED);
+        return Err(StatusCode::UNAUTHORIZED.into_response());
     }
 
+    let now = chrono::Utc::now().naive_utc();
+    let time = query.time
+        .map(|time| parse_timestamp(&time, now))
+        .transpose()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e).into_response())?;
+
+    // Labels are put in order so that they always make the same series
+    let series = match series.contains('{') {
+        true => labels::validate_series(&series).map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid series name: {e}")).into_response())?,
+        false => series,
+    };
+
     match value.classify() {
         FpCategory::Normal | FpCategory::Zero => {
-            let mut db = env.db
-                .acquire()
-                .await
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-            db::put(&mut db, &series, value)
+            env.db.put(&series, value, time)
                 .await
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
         },
         _ => {}
     }
@@ -97,10 +138,535 @@ pub(crate) async fn put(
     Ok("OK".to_string())
 }
 
-pub(crate) async fn build_main(config: &Dashboard, db: &mut SqliteConnection, range_seconds: u32) -> anyhow::Result<MainTemplate> {
+/// Parses a point timestamp given either as unix seconds or as an RFC3339 string.
+/// Timestamps further than `MAX_CLOCK_SKEW_SECONDS` into the future are rejected.
+fn parse_timestamp(text: &str, now: NaiveDateTime) -> Result<NaiveDateTime, String> {
+    check_clock_skew(parse_time(text)?, now)
+}
+
+fn check_clock_skew(time: NaiveDateTime, now: NaiveDateTime) -> Result<NaiveDateTime, String> {
+    if (time - now).num_seconds() > MAX_CLOCK_SKEW_SECONDS {
+        return Err(format!("Time is more than {MAX_CLOCK_SKEW_SECONDS} seconds in the future"));
+    }
+    Ok(time)
+}
+
+#[derive(Deserialize)]
+struct BatchItem {
+    series: String,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    value: f64,
+    time: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct BatchItemError {
+    index: usize,
+    error: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct BatchResult {
+    written: usize,
+    errors: Vec<BatchItemError>,
+}
+
+pub(crate) async fn put_batch(
+    Path(secret): Path<String>,
+    State(env): State<Environment>,
+    Json(items): Json<Vec<serde_json::Value>>,
+) -> Result<Json<BatchResult>, StatusCode> {
+    if secret != env.settings.secret {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let now = chrono::Utc::now().naive_utc();
+    let mut points = vec![];
+    let mut errors = vec![];
+    for (index, item) in items.into_iter().enumerate() {
+        match parse_batch_item(item, now) {
+            Ok(point) => points.push(point),
+            Err(error) => errors.push(BatchItemError{ index, error }),
+        }
+    }
+
+    let written = points.len();
+    env.db.put_all(points)
+        .await
+        .map_err(|e| {
+            println!("Error while writing batch: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(BatchResult{ written, errors }))
+}
+
+fn parse_batch_item(item: serde_json::Value, now: NaiveDateTime) -> Result<(String, NaiveDateTime, f64), String> {
+    let item: BatchItem = serde_json::from_value(item).map_err(|e| e.to_string())?;
+    let (name, mut labels) = labels::parse_series(&item.series).map_err(|e| format!("Invalid series name: {e}"))?;
+    for (key, value) in item.labels {
+        labels::validate_label(&key, &value)?;
+        labels.insert(key, value);
+    }
+    match item.value.classify() {
+        FpCategory::Normal | FpCategory::Zero => {},
+        _ => return Err("Value must be a finite number".to_string())
+    }
+    let time = match item.time {
+        Some(serde_json::Value::Number(seconds)) => parse_timestamp(&seconds.to_string(), now)?,
+        Some(serde_json::Value::String(text)) => parse_timestamp(&text, now)?,
+        Some(_) => return Err("Time must be unix seconds or RFC3339, e.g. 2024-06-13T15:30:00Z".to_string()),
+        None => now
+    };
+    Ok((labels::series_name(&name, &labels), time, item.value))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ExportQuery {
+    /// Comma separated series names or globs
+    series: String,
+    from: Option<String>,
+    to: Option<String>,
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+/// Downloads the points of one or more series in the same formats as `slapdash export`
+pub(crate) async fn export(
+    Path(secret): Path<String>,
+    Query(query): Query<ExportQuery>,
+    State(env): State<Environment>,
+) -> Result<Response, Response> {
+    if secret != env.settings.secret {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let series: Vec<_> = query.series.split(',').map(str::to_string).collect();
+    let from = query.from.as_deref().map(parse_time).transpose().map_err(|e| (StatusCode::BAD_REQUEST, e).into_response())?;
+    let to = query.to.as_deref().map(parse_time).transpose().map_err(|e| (StatusCode::BAD_REQUEST, e).into_response())?;
+
+    let series = export::resolve(&*env.db, &series)
+        .await
+        .map_err(|e| match e {
+            ResolveError::Invalid(message) => (StatusCode::BAD_REQUEST, message).into_response(),
+            ResolveError::NoMatch(_) => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+            ResolveError::Storage(e) => {
+                println!("Error while exporting: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        })?;
+
+    // The points are read and sent a series at a time. An error after the first chunk has been
+    // sent can only be reported by ending the download early.
+    let (tx, rx) = tokio::sync::mpsc::channel::<std::io::Result<Vec<u8>>>(1);
+    let format = query.format;
+    tokio::spawn(async move {
+        let out = SharedBuffer::default();
+        let result = async {
+            let mut exporter = Exporter::new(format, out.clone())?;
+            for name in series {
+                let points = env.db.get_points(&name, from, to).await?;
+                exporter.write_series(&name, &points)?;
+                tx.send(Ok(out.take())).await?;
+            }
+            exporter.finish()?;
+            tx.send(Ok(out.take())).await?;
+            anyhow::Ok(())
+        }.await;
+        if let Err(e) = result {
+            println!("Error while exporting: {}", e);
+            let _ = tx.send(Err(std::io::Error::other(e.to_string()))).await;
+        }
+    });
+
+    let disposition = format!("attachment; filename=\"slapdash.{}\"", query.format.extension());
+    let body = Body::from_stream(ReceiverStream::new(rx));
+    Ok(([(header::CONTENT_TYPE, query.format.content_type().to_string()), (header::CONTENT_DISPOSITION, disposition)], body).into_response())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SeriesQuery {
+    range: Option<u32>,
+    from: Option<String>,
+    to: Option<String>,
+    offset: Option<u32>,
+    /// Width of the buckets in seconds
+    step: Option<u32>,
+    /// How each bucket is summarized. Without it every summary is given.
+    agg: Option<Aggregation>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SeriesData {
+    from: String,
+    to: String,
+    /// Width of the buckets in seconds
+    step: i64,
+    series: Vec<SeriesBuckets>,
+}
+
+#[derive(Serialize)]
+struct SeriesBuckets {
+    name: String,
+    points: Vec<BucketData>,
+}
+
+#[derive(Serialize)]
+struct BucketData {
+    time: String,
+    #[serde(flatten)]
+    values: BucketValues,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum BucketValues {
+    All{ first: f64, last: f64, min: f64, max: f64, avg: f64 },
+    Envelope{ min: f64, max: f64 },
+    One{ value: f64 },
+}
+
+/// Buckets a range may be split into. Longer ranges need a longer `step`.
+const MAX_STEPS: i64 = 10_000;
+
+/// Returns the buckets of the series given by a selector or expression over a range, as they are
+/// drawn by a line widget
+pub(crate) async fn get_series(
+    Path((secret, series)): Path<(String, String)>,
+    Query(query): Query<SeriesQuery>,
+    State(env): State<Environment>,
+) -> Result<Json<SeriesData>, Response> {
+    if secret != env.settings.secret {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let bad_request = |e: String| (StatusCode::BAD_REQUEST, e).into_response();
+    let range_query = DashboardQuery{ range: query.range, from: query.from.clone(), to: query.to.clone(), offset: query.offset };
+    let range = resolve_range(&range_query, chrono::Utc::now().naive_utc()).map_err(bad_request)?;
+    let points = match query.step {
+        None => db::MAX_POINTS,
+        Some(0) => return Err(bad_request("The step must be more than 0 seconds".to_string())),
+        Some(step) => range.seconds.div_ceil(step) as i64,
+    };
+    if points > MAX_STEPS {
+        return Err(bad_request(format!("The range has more than {MAX_STEPS} steps, use a longer step")));
+    }
+    let expr = Expr::parse(&series).map_err(|e| bad_request(format!("Invalid series expression {series}: {e}")))?;
+    let buckets = query.agg.map(|agg| agg.buckets(points)).unwrap_or(points);
+
+    let result = expr.get(&*env.db, range, buckets)
+        .await
+        .map_err(|e| {
+            println!("Error while reading series: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+    if let (Some(selector), []) = (expr.selector(), result.as_slice()) {
+        return Err((StatusCode::NOT_FOUND, format!("No series matches {selector}")).into_response());
+    }
+
+    let series = result
+        .into_iter()
+        .map(|(name, buckets)| {
+            let points = match query.agg {
+                None => buckets
+                    .iter()
+                    .map(|b| BucketData{ time: rfc3339(b.time), values: BucketValues::All{ first: b.first, last: b.last, min: b.min, max: b.max, avg: b.avg } })
+                    .collect(),
+                Some(Aggregation::Envelope) => buckets
+                    .iter()
+                    .map(|b| BucketData{ time: rfc3339(b.time), values: BucketValues::Envelope{ min: b.min, max: b.max } })
+                    .collect(),
+                Some(agg) => agg.apply(&buckets, points as usize).0
+                    .into_iter()
+                    .map(|p| BucketData{ time: rfc3339(p.time), values: BucketValues::One{ value: p.value } })
+                    .collect(),
+            };
+            SeriesBuckets{ name, points }
+        })
+        .collect();
+
+    Ok(Json(SeriesData{
+        from: rfc3339(range.start()),
+        to: rfc3339(range.end()),
+        step: db::bucket_millis(range.seconds, points) / 1000,
+        series,
+    }))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SelectorQuery {
+    /// Selects the series, all of them if not given
+    series: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SeriesSummary {
+    name: String,
+    points: i64,
+    first: Option<String>,
+    last: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct LatestPoint {
+    name: String,
+    time: String,
+    value: f64,
+}
+
+fn parse_selector(query: &SelectorQuery) -> Result<Option<Selector>, (StatusCode, String)> {
+    query.series
+        .as_deref()
+        .map(|selector| Selector::parse(selector).map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid selector {selector}: {e}"))))
+        .transpose()
+}
+
+/// Lists the series with the number and time span of their points
+pub(crate) async fn list_series(
+    Path(secret): Path<String>,
+    Query(query): Query<SelectorQuery>,
+    State(env): State<Environment>,
+) -> Result<Json<Vec<SeriesSummary>>, Response> {
+    if secret != env.settings.secret {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let selector = parse_selector(&query).map_err(IntoResponse::into_response)?;
+    let internal_error = |e: anyhow::Error| {
+        println!("Error while listing series: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    };
+    let names = match selector {
+        Some(selector) => Some(selector.resolve(&*env.db).await.map_err(internal_error)?),
+        None => None,
+    };
+    let series = env.db.list_series_info(None).await.map_err(internal_error)?;
+
+    Ok(Json(series
+        .into_iter()
+        .filter(|info| names.as_ref().is_none_or(|names| names.contains(&info.name)))
+        .map(|info| SeriesSummary{ name: info.name, points: info.points, first: info.first.map(rfc3339), last: info.last.map(rfc3339) })
+        .collect()))
+}
+
+/// Returns the last point of each series
+pub(crate) async fn get_latest(
+    Path(secret): Path<String>,
+    Query(query): Query<SelectorQuery>,
+    State(env): State<Environment>,
+) -> Result<Json<Vec<LatestPoint>>, Response> {
+    if secret != env.settings.secret {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let selector = parse_selector(&query).map_err(IntoResponse::into_response)?;
+    let internal_error = |e: anyhow::Error| {
+        println!("Error while reading latest points: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    };
+    let names = match selector {
+        Some(selector) => selector.resolve(&*env.db).await.map_err(internal_error)?,
+        None => env.db.list_series().await.map_err(internal_error)?.into_iter().map(|(_, name)| name).collect(),
+    };
+    let mut latest = vec![];
+    for name in names {
+        if let Some(point) = env.db.get_last(&name, None).await.map_err(internal_error)? {
+            latest.push(LatestPoint{ name, time: rfc3339(point.time), value: point.value });
+        }
+    }
+    Ok(Json(latest))
+}
+
+fn rfc3339(time: NaiveDateTime) -> String {
+    time.and_utc().trunc_subsecs(3).to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+/// Returns the unit, description, decimal places and expected range of a series as JSON
+pub(crate) async fn get_metadata(
+    Path((secret, series)): Path<(String, String)>,
+    State(env): State<Environment>,
+) -> Result<Json<SeriesMetadata>, StatusCode> {
+    if secret != env.settings.secret {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let metadata = env.db.get_metadata(&labels::canonical(&series))
+        .await
+        .map_err(|e| {
+            println!("Error while reading metadata: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(metadata))
+}
+
+/// Replaces the metadata of a series with the JSON body. Fields which are left out are cleared.
+pub(crate) async fn put_metadata(
+    Path((secret, series)): Path<(String, String)>,
+    State(env): State<Environment>,
+    Json(metadata): Json<SeriesMetadata>,
+) -> Result<Json<SeriesMetadata>, Response> {
+    if secret != env.settings.secret {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let series = labels::validate_series(&series).map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid series name: {e}")).into_response())?;
+    metadata.validate().map_err(|e| (StatusCode::BAD_REQUEST, e).into_response())?;
+    env.db.set_metadata(&series, &metadata)
+        .await
+        .map_err(|e| {
+            println!("Error while writing metadata: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+
+    Ok(Json(metadata))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct WebhookQuery {
+    token: Option<String>,
+}
+
+/// Accepts a JSON body at a webhook route configured in `collectors.xml`, writing the values
+/// mapped by the route. The route's token may be given as the `token` query parameter or as an
+/// `Authorization: Bearer <token>` header.
+pub(crate) async fn receive_webhook(
+    Path(name): Path<String>,
+    Query(query): Query<WebhookQuery>,
+    State(env): State<Environment>,
+    headers: HeaderMap,
+    Json(body): Json<serde_json::Value>,
+) -> Result<Json<BatchResult>, Response> {
+    let config = env.collectors.webhooks
+        .iter()
+        .find(|w| w.name == name)
+        .ok_or(StatusCode::NOT_FOUND.into_response())?;
+
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    if query.token.as_deref().or(bearer) != Some(config.token.as_str()) {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let now = chrono::Utc::now().naive_utc();
+    let (points, errors) = webhook::to_points(config, &body, |time| parse_timestamp(time, now), now)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e).into_response())?;
+
+    let written = points.len();
+    env.db.put_all(points)
+        .await
+        .map_err(|e| {
+            println!("Error while writing webhook {name}: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+
+    let errors = errors
+        .into_iter()
+        .map(|(index, error)| BatchItemError{ index, error })
+        .collect();
+    Ok(Json(BatchResult{ written, errors }))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct InfluxWriteQuery {
+    /// InfluxDB 1.x clients send the password as a query parameter
+    p: Option<String>,
+    precision: Option<String>,
+}
+
+/// Accepts InfluxDB line protocol as sent to the `/write` (1.x) and `/api/v2/write` (2.x) endpoints.
+/// The secret may be given as the password, either in the `p` query parameter or using basic auth,
+/// or as a token in an `Authorization: Token <secret>` header.
+pub(crate) async fn write_influx(
+    Query(query): Query<InfluxWriteQuery>,
+    State(env): State<Environment>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, Response> {
+    if !influx_authorized(&env.settings.secret, query.p.as_deref(), &headers) {
+        return Err(influx_error(StatusCode::UNAUTHORIZED, "authorization failed".to_string()));
+    }
+
+    let precision = influx::Precision::parse(query.precision.as_deref())
+        .map_err(|e| influx_error(StatusCode::BAD_REQUEST, e))?;
+    let body = decode_body(&headers, &body)
+        .map_err(|e| influx_error(StatusCode::BAD_REQUEST, e))?;
+
+    let now = chrono::Utc::now().naive_utc();
+    let (points, mut errors) = influx::parse(&body, precision, now);
+    let points = points
+        .into_iter()
+        .filter(|(series, time, _)| match check_clock_skew(*time, now) {
+            Ok(_) => true,
+            Err(e) => {
+                errors.push(format!("{series}: {e}"));
+                false
+            }
+        })
+        .collect();
+
+    env.db.put_all(points)
+        .await
+        .map_err(|e| {
+            println!("Error while writing line protocol: {}", e);
+            influx_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        })?;
+
+    if !errors.is_empty() {
+        return Err(influx_error(StatusCode::BAD_REQUEST, format!("partial write: {}", errors.join("; "))));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn influx_authorized(secret: &str, password: Option<&str>, headers: &HeaderMap) -> bool {
+    if password == Some(secret) {
+        return true;
+    }
+
+    let Some(authorization) = headers.get(header::AUTHORIZATION).and_then(|h| h.to_str().ok()) else {
+        return false;
+    };
+
+    if let Some(token) = authorization.strip_prefix("Token ") {
+        return token == secret;
+    }
+
+    authorization
+        .strip_prefix("Basic ")
+        .and_then(|credentials| BASE64_STANDARD.decode(credentials).ok())
+        .and_then(|credentials| String::from_utf8(credentials).ok())
+        .and_then(|credentials| credentials.split_once(':').map(|(_, password)| password == secret))
+        .unwrap_or(false)
+}
+
+fn influx_error(status: StatusCode, error: String) -> Response {
+    (status, Json(serde_json::json!({ "error": error }))).into_response()
+}
+
+fn decode_body(headers: &HeaderMap, body: &[u8]) -> Result<String, String> {
+    let gzip = headers
+        .get(header::CONTENT_ENCODING)
+        .is_some_and(|encoding| encoding.as_bytes().eq_ignore_ascii_case(b"gzip"));
+
+    if gzip {
+        let mut text = String::new();
+        GzDecoder::new(body)
+            .read_to_string(&mut text)
+            .map_err(|e| format!("Failed to decompress body: {e}"))?;
+        Ok(text)
+    } else {
+        String::from_utf8(body.to_vec()).map_err(|_| "Body must be UTF-8".to_string())
+    }
+}
+
+pub(crate) async fn build_main(config: &Dashboard, db: &dyn Storage, range: TimeRange) -> anyhow::Result<MainTemplate> {
     let mut widget_templates = vec![];
     for widget_config in config.widgets.iter() {
-        let widget_template = widget_config.to_template(db, range_seconds).await?;
+        let widget_template = widget_config.to_template(db, range).await?;
         widget_templates.push(widget_template)
     }
 
@@ -195,4 +761,166 @@ pub(crate) async fn build_main(config: &Dashboard, db: &mut SqliteConnection, ra
 //             template,
 //         }
 //     )
-// }
\ No newline at end of file
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    #[test]
+    fn test_parse_timestamp() {
+        let now = DateTime::from_timestamp(1_718_292_600, 0).unwrap().naive_utc();
+
+        let unix = parse_timestamp("1718289000", now).unwrap();
+        let rfc3339 = parse_timestamp("2024-06-13T15:30:00+01:00", now).unwrap();
+        assert_eq!(unix, rfc3339);
+
+        assert!(parse_timestamp("1718292900", now).is_ok());
+        assert!(parse_timestamp("1718292901", now).is_err());
+        assert!(parse_timestamp("2024-06-13 15:30:00", now).is_err());
+
+        let millis = parse_timestamp("1718289000.125", now).unwrap();
+        assert_eq!(millis, parse_timestamp("2024-06-13T14:30:00.125Z", now).unwrap());
+        assert_eq!(millis.and_utc().timestamp_millis(), 1_718_289_000_125);
+    }
+
+    #[test]
+    fn test_resolve_range() {
+        let now = DateTime::from_timestamp(1_718_292_600, 0).unwrap().naive_utc();
+        let time = |seconds: i64| Some(DateTime::from_timestamp(seconds, 0).unwrap().naive_utc());
+        let query = |range: Option<u32>, from: Option<&str>, to: Option<&str>, offset: Option<u32>| DashboardQuery{
+            range,
+            from: from.map(str::to_string),
+            to: to.map(str::to_string),
+            offset,
+        };
+
+        assert_eq!(resolve_range(&DashboardQuery::default(), now), Ok(TimeRange::last(86400)));
+        assert_eq!(resolve_range(&query(Some(3600), None, None, Some(600)), now), Ok(TimeRange{ end: time(1_718_292_000), seconds: 3600 }));
+        assert_eq!(resolve_range(&query(None, Some("1718200000"), Some("2024-06-12T14:46:40Z"), None), now), Ok(TimeRange{ end: time(1_718_203_600), seconds: 3600 }));
+        assert_eq!(resolve_range(&query(Some(60), Some("1718200000"), None, Some(60)), now), Ok(TimeRange{ end: time(1_718_200_000), seconds: 60 }));
+        assert_eq!(resolve_range(&query(None, Some("1718292000"), None, None), now), Ok(TimeRange::last(600)));
+        assert_eq!(resolve_range(&query(Some(60), None, Some("1718200000"), None), now), Ok(TimeRange{ end: time(1_718_200_000), seconds: 60 }));
+
+        assert!(resolve_range(&query(Some(0), None, None, None), now).is_err());
+        assert!(resolve_range(&query(None, Some("1718200000"), Some("1718100000"), None), now).is_err());
+        assert!(resolve_range(&query(Some(60), Some("1718100000"), Some("1718200000"), None), now).is_err());
+        assert!(resolve_range(&query(None, None, Some("last tuesday"), None), now).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_api() {
+        let dir = std::env::temp_dir().join(format!("slapdash-read-api-{}", std::process::id()));
+        let env = Environment::for_test(&dir).await;
+        let secret = || env.settings.secret.clone();
+        // A point every minute of an hour, 30 s after each minute
+        let start = 1_718_200_200;
+        let time = |i: i64| DateTime::from_timestamp(start + 30 + 60 * i, 0).unwrap().naive_utc();
+        let mut points: Vec<_> = (0..60).map(|i| ("power".to_string(), time(i), i as f64)).collect();
+        points.push(("power{mppt=\"a\"}".to_string(), time(0), -1.0));
+        env.db.put_all(points).await.unwrap();
+
+        let query = |step: Option<u32>, agg: Option<Aggregation>| SeriesQuery{
+            range: None,
+            from: Some(start.to_string()),
+            to: Some((start + 3600).to_string()),
+            offset: None,
+            step,
+            agg,
+        };
+        let get = async |series: &str, query: SeriesQuery| get_series(Path((secret(), series.to_string())), Query(query), State(env.clone())).await;
+        let values = async |agg: Aggregation| {
+            let Json(data) = get("power", query(Some(600), Some(agg))).await.unwrap();
+            serde_json::to_value(&data.series[0].points[1]).unwrap()
+        };
+
+        // A step of 10 minutes gives 6 buckets of 10 points
+        let Json(data) = get("power", query(Some(600), None)).await.unwrap();
+        assert_eq!((data.step, data.series.len(), data.series[0].points.len()), (600, 1, 6));
+        assert_eq!(
+            serde_json::to_value(&data.series[0].points[1]).unwrap(),
+            serde_json::json!({"time": "2024-06-12T14:00:30Z", "first": 10.0, "last": 19.0, "min": 10.0, "max": 19.0, "avg": 14.5}),
+        );
+        assert_eq!(values(Aggregation::First).await["value"], 10.0);
+        assert_eq!(values(Aggregation::Last).await["value"], 19.0);
+        assert_eq!(values(Aggregation::Avg).await["value"], 14.5);
+        assert_eq!(values(Aggregation::Min).await["value"], 10.0);
+        assert_eq!(values(Aggregation::Max).await["value"], 19.0);
+        assert_eq!(values(Aggregation::Envelope).await, serde_json::json!({"time": "2024-06-12T14:00:30Z", "min": 10.0, "max": 19.0}));
+        let Json(data) = get("power", query(Some(600), Some(Aggregation::Lttb))).await.unwrap();
+        assert_eq!(data.series[0].points.len(), 6);
+
+        // Without a step the range is split into 512 buckets, one per point
+        let Json(data) = get("power", query(None, None)).await.unwrap();
+        assert_eq!((data.step, data.series[0].points.len()), (7, 60));
+
+        assert_eq!(get("power", query(Some(0), None)).await.err().map(|e| e.status()), Some(StatusCode::BAD_REQUEST));
+        let long = SeriesQuery{ range: Some(86_400), from: None, to: None, offset: None, step: Some(1), agg: None };
+        assert_eq!(get("power", long).await.err().map(|e| e.status()), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(get("power{mppt=\"b\"}", query(None, None)).await.err().map(|e| e.status()), Some(StatusCode::NOT_FOUND));
+
+        let list = async |series: Option<&str>| {
+            let query = SelectorQuery{ series: series.map(str::to_string) };
+            let Json(series) = list_series(Path(secret()), Query(query), State(env.clone())).await.unwrap();
+            series.into_iter().map(|s| (s.name, s.points)).collect::<Vec<_>>()
+        };
+        assert_eq!(list(None).await, vec![("power".to_string(), 60), ("power{mppt=\"a\"}".to_string(), 1)]);
+        assert_eq!(list(Some("power")).await, vec![("power".to_string(), 60)]);
+        assert_eq!(list(Some("power{mppt=\"a\"}")).await, vec![("power{mppt=\"a\"}".to_string(), 1)]);
+
+        let latest = async |series: Option<&str>| {
+            let query = SelectorQuery{ series: series.map(str::to_string) };
+            let Json(latest) = get_latest(Path(secret()), Query(query), State(env.clone())).await.unwrap();
+            latest.into_iter().map(|p| (p.name, p.time, p.value)).collect::<Vec<_>>()
+        };
+        assert_eq!(latest(Some("power")).await, vec![("power".to_string(), "2024-06-12T14:49:30Z".to_string(), 59.0)]);
+        assert_eq!(latest(Some("power{}")).await.len(), 2);
+        let status = get_latest(Path(secret()), Query(SelectorQuery{ series: Some("power{mppt=".to_string()) }), State(env.clone())).await.err().map(|e| e.status());
+        assert_eq!(status, Some(StatusCode::BAD_REQUEST));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_parse_batch_item() {
+        let now = DateTime::from_timestamp(1_718_292_600, 0).unwrap().naive_utc();
+        let item = serde_json::json!({"series": "cpu{host=\"a\"}", "labels": {"core": "0"}, "value": 0.5, "time": 1_718_292_000});
+        let (series, time, value) = parse_batch_item(item, now).unwrap();
+        assert_eq!(series, labels::series_name("cpu", &BTreeMap::from([("core".to_string(), "0".to_string()), ("host".to_string(), "a".to_string())])));
+        assert_eq!(time.and_utc().timestamp(), 1_718_292_000);
+        assert_eq!(value, 0.5);
+
+        assert_eq!(parse_batch_item(serde_json::json!({"series": "cpu", "value": 1}), now).unwrap().1, now);
+        for value in ["NaN", "inf", "-inf"] {
+            assert!(parse_batch_item(serde_json::json!({"series": "cpu", "value": value}), now).is_err());
+        }
+        assert!(parse_batch_item(serde_json::json!({"series": "cpu", "value": 1, "time": true}), now).is_err());
+        assert!(parse_batch_item(serde_json::json!({"series": "cpu", "value": 1, "time": 1_718_293_000}), now).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_put_batch_writes_valid_items() {
+        let dir = std::env::temp_dir().join(format!("slapdash-batch-{}", std::process::id()));
+        let env = Environment::for_test(&dir).await;
+        let items = serde_json::json!([
+            {"series": "temperature", "value": 21.5},
+            {"series": "temperature", "value": "warm"},
+            {"series": "humidity", "value": 40, "time": "2024-06-13T15:30:00Z"},
+            {"series": "bad{label", "value": 1},
+            {"value": 1},
+        ]);
+        let items = serde_json::from_value(items).unwrap();
+
+        let Json(result) = put_batch(Path(env.settings.secret.clone()), State(env.clone()), Json(items)).await.unwrap();
+        assert_eq!(result.written, 2);
+        assert_eq!(result.errors.iter().map(|e| e.index).collect::<Vec<_>>(), vec![1, 3, 4]);
+        assert_eq!(env.db.get_last("temperature", None).await.unwrap().map(|p| p.value), Some(21.5));
+        assert_eq!(env.db.get_last("humidity", None).await.unwrap().map(|p| p.value), Some(40.0));
+
+        let status = put_batch(Path("wrong".to_string()), State(env.clone()), Json(vec![])).await.err();
+        assert_eq!(status, Some(StatusCode::UNAUTHORIZED));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,18 +1,47 @@
+use std::collections::HashMap;
+use crate::labels;
 use crate::model::*;
 use sqlx::types::chrono::NaiveDateTime;
 use sqlx::Connection;
 
-const MAX_POINTS: i64 = 512;
+/// The number of buckets that a line widget displays
+pub(crate) const MAX_POINTS: i64 = 512;
+/// Bucket sizes in seconds that points are rolled up into: hourly and daily
+pub(crate) const ROLLUP_RESOLUTIONS: [i64; 2] = [60 * 60, 24 * 60 * 60];
 
-pub(crate) async fn put_all(db: &mut sqlx::SqliteConnection, points: Vec<(String, NaiveDateTime, f32)>) -> anyhow::Result<()> {
+/// Writes points in a single transaction. `series_ids` caches the ids of series by name between
+/// calls, so that most points are written with a single insert.
+pub(crate) async fn put_all(db: &mut sqlx::SqliteConnection, points: Vec<(String, NaiveDateTime, f64)>, series_ids: &mut HashMap<String, i64>) -> anyhow::Result<()> {
     let mut tx = db
         .begin()
         .await
         .map_err(|_| anyhow::anyhow!("Failed to begin transaction"))?;
 
     for (series, time, value) in points {
-        // First, insert the series (or ignore if it already exists)
-        sqlx::query!("
+        let timestamp = time.and_utc().timestamp_millis();
+
+        // A cached id is only used while it still has the same name, as the series may have been
+        // renamed or deleted by another process
+        if let Some(&series_id) = series_ids.get(&series) {
+            let result = sqlx::query!("
+                    INSERT INTO point (series_id, time, value)
+                    SELECT id, ?, ? FROM series WHERE id = ? AND name = ?
+                ",
+                timestamp,
+                value,
+                series_id,
+                series
+                )
+                .execute(&mut *tx)
+                .await
+                .map_err(|_| anyhow::anyhow!("Failed to insert point"))?;
+            if result.rows_affected() == 1 {
+                continue;
+            }
+        }
+
+        // Otherwise insert the series (or ignore if it already exists) and look up its id
+        let inserted = sqlx::query!("
                 INSERT OR IGNORE INTO series (name) 
                 VALUES (?)
             ",
@@ -21,25 +50,26 @@ pub(crate) async fn put_all(db: &mut sqlx::SqliteConnection, points: Vec<(String
             .execute(&mut *tx)
             .await
             .map_err(|e| anyhow::anyhow!("Failed to insert series: {}", e))?;
+        let series_id = sqlx::query_scalar!(r#"SELECT id as "id!: i64" FROM series WHERE name = ?"#, series)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to find series: {}", e))?;
+        if inserted.rows_affected() == 1 {
+            set_labels(&mut tx, series_id, &series).await?;
+        }
 
-        let timestamp = time.and_utc().timestamp();
-
-        // Then insert the point
         sqlx::query!("
                 INSERT INTO point (series_id, time, value)
-                VALUES (
-                    (SELECT id FROM series WHERE name = ?),
-                    ?,
-                    ?
-                )
+                VALUES (?, ?, ?)
             ",
-            series,
+            series_id,
             timestamp,
             value
             )
             .execute(&mut *tx)
             .await
             .map_err(|_| anyhow::anyhow!("Failed to insert point"))?;
+        series_ids.insert(series, series_id);
     }
 
     tx.commit().await.map_err(|_| anyhow::anyhow!("Failed to commit transaction"))?;
@@ -47,115 +77,688 @@ pub(crate) async fn put_all(db: &mut sqlx::SqliteConnection, points: Vec<(String
     Ok(())
 }
 
-pub(crate) async fn put(db: &mut sqlx::SqliteConnection, series: &str, point: f32) -> anyhow::Result<()> {
+/// Returns up to `buckets` buckets of a series over the range, each summarizing the points in it.
+/// Long ranges are read from the coarsest rollup which still gives that many buckets, plus the
+/// raw points which haven't been rolled up yet. Buckets are `bucket_millis` wide and start at a
+/// multiple of it, so the buckets of different series line up.
+pub(crate) async fn get(db: &mut sqlx::SqliteConnection, series: &str, range: TimeRange, buckets: i64) -> anyhow::Result<Vec<Bucket>>{
+    let resolution = rollup_resolution(range.seconds, buckets);
+    let width = bucket_millis(range.seconds, buckets);
+    let (start, end) = range.millis();
+    let buckets = sqlx::query_as!(
+            Bucket,
+            "
+            WITH filtered AS (
+                SELECT r.first_time AS time, r.last_time, r.first, r.last, r.min, r.max, r.sum, r.count
+                FROM rollup r
+                WHERE r.series_id = (SELECT id FROM series WHERE name = $1 LIMIT 1)
+                    AND r.resolution = $4
+                    AND r.time > $2 AND r.time <= $5
+                UNION ALL
+                SELECT p.time, p.time, p.value, p.value, p.value, p.value, p.value, 1
+                FROM point p
+                WHERE p.series_id = (SELECT id FROM series WHERE name = $1 LIMIT 1)
+                    AND p.time > $2 AND p.time <= $5
+                    AND p.time >= COALESCE((SELECT rolled_until FROM rollup_state WHERE resolution = $4), 0)
+            ),
+            bucketed AS (
+                SELECT f.*, f.time / $3 AS bucket
+                FROM filtered f
+            ),
+            ranked AS (
+                SELECT
+                    *,
+                    ROW_NUMBER() OVER (PARTITION BY bucket ORDER BY time ASC) AS rn_first,
+                    ROW_NUMBER() OVER (PARTITION BY bucket ORDER BY last_time DESC) AS rn_last
+                FROM bucketed
+            )
+            SELECT
+                strftime('%Y-%m-%d %H:%M:%f', MIN(time) / 1000.0, 'unixepoch') as `time!: NaiveDateTime`,
+                CAST(MAX(CASE WHEN rn_first = 1 THEN first END) AS REAL) as `first!: f64`,
+                CAST(MAX(CASE WHEN rn_last = 1 THEN last END) AS REAL) as `last!: f64`,
+                CAST(MIN(min) AS REAL) as `min!: f64`,
+                CAST(MAX(max) AS REAL) as `max!: f64`,
+                CAST(SUM(sum) / SUM(count) AS REAL) as `avg!: f64`
+            FROM ranked
+            GROUP BY bucket
+            ORDER BY MIN(time) ASC
+            ",
+            series,
+            start,
+            width,
+            resolution,
+            end
+        )
+        .fetch_all(db)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to fetch points: {}", e))?;
+
+    Ok(buckets)
+}
+
+/// The width in milliseconds of the buckets `get` returns for a range: the range divided into
+/// `buckets`, rounded up to whole milliseconds or to the rollup resolution read from
+pub(crate) fn bucket_millis(range_seconds: u32, buckets: i64) -> i64 {
+    let unit = match rollup_resolution(range_seconds, buckets) {
+        0 => 1,
+        resolution => resolution * 1000,
+    };
+    let buckets = buckets.max(1);
+    let width = (range_seconds as i64 * 1000 + buckets - 1) / buckets;
+    ((width + unit - 1) / unit).max(1) * unit
+}
+
+/// The coarsest rollup resolution which still gives `buckets` buckets over the range, or 0 for
+/// raw points
+fn rollup_resolution(range_seconds: u32, buckets: i64) -> i64 {
+    ROLLUP_RESOLUTIONS
+        .iter()
+        .rev()
+        .find(|resolution| range_seconds as i64 / **resolution >= buckets)
+        .copied()
+        .unwrap_or(0)
+}
+
+/// Aggregates the points before `until` which haven't been rolled up yet into buckets of
+/// `resolution` seconds. Only whole buckets are rolled up. Points which arrive late, with a time
+/// that has already been rolled up, are merged into their existing bucket.
+pub(crate) async fn roll_up(db: &mut sqlx::SqliteConnection, resolution: i64, until: NaiveDateTime) -> anyhow::Result<()> {
     let mut tx = db
         .begin()
         .await
         .map_err(|_| anyhow::anyhow!("Failed to begin transaction"))?;
 
-    // First, insert the series (or ignore if it already exists)
-    sqlx::query!("
-            INSERT OR IGNORE INTO series (name) 
-            VALUES (?)
+    let (rolled_until, last_point_id) = sqlx::query!("
+            SELECT rolled_until, last_point_id FROM rollup_state WHERE resolution = ?
         ",
-        series
+        resolution
         )
-        .execute(&mut *tx)
+        .fetch_optional(&mut *tx)
         .await
-        .map_err(|e| anyhow::anyhow!("Failed to insert series: {}", e))?;
+        .map_err(|e| anyhow::anyhow!("Failed to read rollup state: {}", e))?
+        .map(|state| (state.rolled_until, state.last_point_id))
+        .unwrap_or((0, 0));
 
-    // Then insert the point, using current unix timestamp for time
-    sqlx::query!("
-        INSERT INTO point (series_id, time, value)
-        VALUES (
-            (SELECT id FROM series WHERE name = ?),
-            strftime('%s','now'),
-            ?
-        )
-    ",
-    series,
-    point
-    )
-    .execute(&mut *tx)
-    .await
-    .map_err(|_| anyhow::anyhow!("Failed to insert point"))?;
+    let max_point_id = sqlx::query_scalar!(r#"SELECT COALESCE(MAX(id), 0) as "id!: i64" FROM point"#)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read last point: {}", e))?;
 
-    tx.commit().await.map_err(|_| anyhow::anyhow!("Failed to commit transaction"))?;
+    let bucket_millis = resolution * 1000;
+    let until = (until.and_utc().timestamp_millis() / bucket_millis * bucket_millis).max(rolled_until);
 
-    Ok(())
-}
-
-pub(crate) async fn get(db: &mut sqlx::SqliteConnection, series: &str, range_seconds: u32) -> anyhow::Result<Vec<Point>>{
-    let points = sqlx::query_as!(
-            Point,
-            "
-            WITH filtered AS (
-                SELECT p.time, p.value
-                FROM point p
-                WHERE p.series_id = (SELECT id FROM series WHERE name = $1 LIMIT 1)
-                    AND p.time > strftime('%s','now') - $2
-            ),
-            bounds AS (
-                SELECT MIN(time) AS min_t, MAX(time) AS max_t
-                FROM filtered
-            ),
-            bucketed AS (
-                SELECT
-                    f.time,
-                    f.value,
-                    CASE
-                        WHEN $3 <= 1 OR b.max_t = b.min_t THEN 0
-                        ELSE CAST((f.time - b.min_t) * $3 / (b.max_t - b.min_t + 1) AS INTEGER)
-                    END AS bucket
-                FROM filtered f
-                CROSS JOIN bounds b
+    sqlx::query!("
+            WITH new AS (
+                SELECT id, series_id, time / ($1 * 1000) * ($1 * 1000) AS bucket, time, value
+                FROM point
+                WHERE id <= $4
+                    AND ((time >= $2 AND time < $3) OR (id > $5 AND time < $2))
             ),
-            picked AS (
+            ranked AS (
                 SELECT
+                    series_id,
+                    bucket,
                     time,
                     value,
-                    ROW_NUMBER() OVER (PARTITION BY bucket ORDER BY time ASC) AS rn
-                FROM bucketed
+                    ROW_NUMBER() OVER (PARTITION BY series_id, bucket ORDER BY time ASC, id ASC) AS rn_first,
+                    ROW_NUMBER() OVER (PARTITION BY series_id, bucket ORDER BY time DESC, id DESC) AS rn_last
+                FROM new
             )
+            INSERT INTO rollup (series_id, resolution, time, count, sum, min, max, first_time, first, last_time, last)
             SELECT
-                datetime(time, 'unixepoch') as `time!: NaiveDateTime`,
-                CAST(value AS REAL) as `value!: f32`
-            FROM picked
-            WHERE rn = 1
-            ORDER BY time ASC
-            ",
-            series,
-            range_seconds,
-            MAX_POINTS
+                series_id,
+                $1,
+                bucket,
+                COUNT(*),
+                SUM(value),
+                MIN(value),
+                MAX(value),
+                MIN(time),
+                MAX(CASE WHEN rn_first = 1 THEN value END),
+                MAX(time),
+                MAX(CASE WHEN rn_last = 1 THEN value END)
+            FROM ranked
+            WHERE true
+            GROUP BY series_id, bucket
+            ON CONFLICT (series_id, resolution, time) DO UPDATE SET
+                count = count + excluded.count,
+                sum = sum + excluded.sum,
+                min = MIN(min, excluded.min),
+                max = MAX(max, excluded.max),
+                first_time = MIN(first_time, excluded.first_time),
+                first = CASE WHEN excluded.first_time < first_time THEN excluded.first ELSE first END,
+                last_time = MAX(last_time, excluded.last_time),
+                last = CASE WHEN excluded.last_time >= last_time THEN excluded.last ELSE last END
+        ",
+        resolution,
+        rolled_until,
+        until,
+        max_point_id,
+        last_point_id
         )
-        .fetch_all(db)
+        .execute(&mut *tx)
         .await
-        .map_err(|e| anyhow::anyhow!("Failed to fetch points: {}", e.to_string()))?;
+        .map_err(|e| anyhow::anyhow!("Failed to roll up points: {}", e))?;
 
-    Ok(points)
+    sqlx::query!("
+            INSERT INTO rollup_state (resolution, rolled_until, last_point_id)
+            VALUES (?, ?, ?)
+            ON CONFLICT (resolution) DO UPDATE SET
+                rolled_until = excluded.rolled_until,
+                last_point_id = excluded.last_point_id
+        ",
+        resolution,
+        until,
+        max_point_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to write rollup state: {}", e))?;
+
+    tx.commit().await.map_err(|_| anyhow::anyhow!("Failed to commit transaction"))?;
+
+    Ok(())
 }
 
-pub(crate) async fn get_last(db: &mut sqlx::SqliteConnection, series: &str) -> anyhow::Result<Option<Point>>{
+/// Returns the last point of a series, or the last at or before `until` if given
+pub(crate) async fn get_last(db: &mut sqlx::SqliteConnection, series: &str, until: Option<NaiveDateTime>) -> anyhow::Result<Option<Point>>{
+    let until = until.map(|t| t.and_utc().timestamp_millis()).unwrap_or(i64::MAX);
     let point = sqlx::query_as!(
         Point,
         "
             SELECT 
-                datetime(time, 'unixepoch') as `time!: NaiveDateTime`, 
-                CAST(value AS REAL) as `value!: f32`
+                strftime('%Y-%m-%d %H:%M:%f', time / 1000.0, 'unixepoch') as `time!: NaiveDateTime`, 
+                CAST(value AS REAL) as `value!: f64`
             FROM 
                 point
             WHERE 
                 series_id = (SELECT id FROM series WHERE name = $1 LIMIT 1)
+                AND time <= $2
             ORDER BY 
-                time DESC
+                time DESC, id DESC
             LIMIT 
                 1
         ",
-        series
+        series,
+        until
     )
     .fetch_optional(db)
     .await
-    .map_err(|e| anyhow::anyhow!("Failed to fetch last point: {}", e.to_string()))?;
+    .map_err(|e| anyhow::anyhow!("Failed to fetch last point: {}", e))?;
 
     Ok(point)
-}
\ No newline at end of file
+}
+/// Returns the raw points of a series at or after `from` and before `to`, oldest first
+pub(crate) async fn get_points(db: &mut sqlx::SqliteConnection, series: &str, from: Option<NaiveDateTime>, to: Option<NaiveDateTime>) -> anyhow::Result<Vec<Point>>{
+    let from = from.map(|t| t.and_utc().timestamp_millis()).unwrap_or(i64::MIN);
+    let to = to.map(|t| t.and_utc().timestamp_millis()).unwrap_or(i64::MAX);
+    let points = sqlx::query_as!(
+        Point,
+        "
+            SELECT
+                strftime('%Y-%m-%d %H:%M:%f', time / 1000.0, 'unixepoch') as `time!: NaiveDateTime`,
+                CAST(value AS REAL) as `value!: f64`
+            FROM
+                point
+            WHERE
+                series_id = (SELECT id FROM series WHERE name = $1 LIMIT 1)
+                AND time >= $2
+                AND time < $3
+            ORDER BY
+                time ASC, id ASC
+        ",
+        series,
+        from,
+        to
+    )
+    .fetch_all(db)
+    .await
+    .map_err(|e| anyhow::anyhow!("Failed to fetch points: {}", e))?;
+
+    Ok(points)
+}
+
+pub(crate) async fn list_series(db: &mut sqlx::SqliteConnection) -> anyhow::Result<Vec<(i64, String)>> {
+    let series = sqlx::query!(r#"SELECT id as "id!: i64", name FROM series ORDER BY name"#)
+        .fetch_all(db)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to list series: {}", e))?;
+
+    Ok(series.into_iter().map(|s| (s.id, s.name)).collect())
+}
+
+/// Returns the series whose names without labels are like `name`, and which have all of `labels`,
+/// or no labels at all if `labels` is not given
+pub(crate) async fn find_series(db: &mut sqlx::SqliteConnection, name: &str, labels: Option<&[(String, String)]>) -> anyhow::Result<Vec<(i64, String)>> {
+    let labels = labels.map(serde_json::to_string).transpose()?;
+    let series = sqlx::query!(
+            r#"
+            SELECT s.id as "id!: i64", s.name
+            FROM series s
+            WHERE (
+                    (instr(s.name, '{') = 0 AND s.name LIKE ?1 ESCAPE '\')
+                    OR (?2 IS NOT NULL AND s.name LIKE ?1 || '{%' ESCAPE '\')
+                )
+                AND (
+                    ?2 IS NULL OR json_array_length(?2) = 0 OR s.id IN (
+                        SELECT l.series_id
+                        FROM series_label l
+                        JOIN json_each(?2) m ON l.key = json_extract(m.value, '$[0]') AND l.value = json_extract(m.value, '$[1]')
+                        GROUP BY l.series_id
+                        HAVING COUNT(*) = json_array_length(?2)
+                    )
+                )
+            ORDER BY s.name
+            "#,
+            name,
+            labels
+        )
+        .fetch_all(db)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to find series: {}", e))?;
+
+    Ok(series.into_iter().map(|s| (s.id, s.name)).collect())
+}
+
+/// Replaces the labels of a series with those in its name
+async fn set_labels(db: &mut sqlx::SqliteConnection, series_id: i64, series: &str) -> anyhow::Result<()> {
+    sqlx::query!("DELETE FROM series_label WHERE series_id = ?", series_id)
+        .execute(&mut *db)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to delete labels: {}", e))?;
+    for (key, value) in labels::labels_of(series).1 {
+        sqlx::query!("INSERT INTO series_label (series_id, key, value) VALUES (?, ?, ?)", series_id, key, value)
+            .execute(&mut *db)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to insert label: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Stores the labels of series with labels in their names which have none stored, as series
+/// written before labels were stored don't
+pub(crate) async fn label_series(db: &mut sqlx::SqliteConnection) -> anyhow::Result<()> {
+    let series = sqlx::query!(r#"
+            SELECT id as "id!: i64", name
+            FROM series
+            WHERE instr(name, '{') > 0 AND id NOT IN (SELECT series_id FROM series_label)
+        "#)
+        .fetch_all(&mut *db)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to list series: {}", e))?;
+
+    let mut tx = db
+        .begin()
+        .await
+        .map_err(|_| anyhow::anyhow!("Failed to begin transaction"))?;
+    for s in series {
+        set_labels(&mut tx, s.id, &s.name).await?;
+    }
+    tx.commit().await.map_err(|_| anyhow::anyhow!("Failed to commit transaction"))?;
+
+    Ok(())
+}
+
+/// Deletes the points of a series older than `time`, returning how many were deleted. The rollup
+/// bucket holding `time` is rebuilt from the points which are kept.
+pub(crate) async fn delete_before(db: &mut sqlx::SqliteConnection, series_id: i64, time: NaiveDateTime) -> anyhow::Result<u64> {
+    let mut tx = db
+        .begin()
+        .await
+        .map_err(|_| anyhow::anyhow!("Failed to begin transaction"))?;
+
+    let timestamp = time.and_utc().timestamp_millis();
+    let result = sqlx::query!("DELETE FROM point WHERE series_id = ? AND time < ?", series_id, timestamp)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to delete points: {}", e))?;
+
+    rebuild_rollups(&mut tx, series_id, i64::MIN, timestamp).await?;
+
+    tx.commit().await.map_err(|_| anyhow::anyhow!("Failed to commit transaction"))?;
+
+    Ok(result.rows_affected())
+}
+
+/// Rebuilds the database file to reclaim the space of deleted points
+pub(crate) async fn vacuum(db: &mut sqlx::SqliteConnection) -> anyhow::Result<()> {
+    sqlx::query!("VACUUM")
+        .execute(db)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to vacuum database: {}", e))?;
+
+    Ok(())
+}
+
+/// Writes a consistent copy of the database to `path`, which must not exist. Other connections may
+/// keep writing while the copy is made.
+pub(crate) async fn backup(db: &mut sqlx::SqliteConnection, path: &str) -> anyhow::Result<()> {
+    sqlx::query!("VACUUM INTO ?", path)
+        .execute(db)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to back up database: {}", e))?;
+
+    Ok(())
+}
+
+/// Checks the database file for corruption
+pub(crate) async fn check_integrity(db: &mut sqlx::SqliteConnection) -> anyhow::Result<()> {
+    let result = sqlx::query_scalar!(r#"PRAGMA integrity_check"#)
+        .fetch_all(db)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to check database: {}", e))?;
+
+    match result.as_slice() {
+        [ok] if ok.as_deref() == Some("ok") => Ok(()),
+        _ => Err(anyhow::anyhow!("Database is corrupt: {}", result.into_iter().flatten().collect::<Vec<_>>().join(", "))),
+    }
+}
+
+/// Returns every series, or only `series` if given, with the number and time span of its points
+pub(crate) async fn list_series_info(db: &mut sqlx::SqliteConnection, series: Option<&str>) -> anyhow::Result<Vec<SeriesInfo>> {
+    let series = sqlx::query_as!(
+            SeriesInfo,
+            r#"
+            SELECT
+                s.name as "name!",
+                COUNT(p.id) as "points!: i64",
+                strftime('%Y-%m-%d %H:%M:%f', MIN(p.time) / 1000.0, 'unixepoch') as "first: NaiveDateTime",
+                strftime('%Y-%m-%d %H:%M:%f', MAX(p.time) / 1000.0, 'unixepoch') as "last: NaiveDateTime"
+            FROM series s
+            LEFT JOIN point p ON p.series_id = s.id
+            WHERE $1 IS NULL OR s.name = $1
+            GROUP BY s.id
+            ORDER BY s.name
+            "#,
+            series
+        )
+        .fetch_all(db)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to list series: {}", e))?;
+
+    Ok(series)
+}
+
+pub(crate) async fn rename_series(db: &mut sqlx::SqliteConnection, from: &str, to: &str) -> anyhow::Result<()> {
+    let mut tx = db
+        .begin()
+        .await
+        .map_err(|_| anyhow::anyhow!("Failed to begin transaction"))?;
+
+    let exists = sqlx::query_scalar!("SELECT id FROM series WHERE name = ?", to)
+        .fetch_optional(&mut *tx)
+        .await?;
+    if exists.is_some() {
+        return Err(anyhow::anyhow!("Series {to} already exists, merge the series instead"));
+    }
+
+    let result = sqlx::query!("UPDATE series SET name = ? WHERE name = ?", to, from)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to rename series: {}", e))?;
+    if result.rows_affected() == 0 {
+        return Err(anyhow::anyhow!("Series {from} not found"));
+    }
+    let series_id = sqlx::query_scalar!(r#"SELECT id as "id!: i64" FROM series WHERE name = ?"#, to)
+        .fetch_one(&mut *tx)
+        .await?;
+    set_labels(&mut tx, series_id, to).await?;
+
+    tx.commit().await.map_err(|_| anyhow::anyhow!("Failed to commit transaction"))?;
+
+    Ok(())
+}
+
+/// Deletes the points of a series between `from` and `to`, returning how many were deleted. If
+/// neither is given the series itself is deleted.
+pub(crate) async fn delete_series(db: &mut sqlx::SqliteConnection, series: &str, from: Option<NaiveDateTime>, to: Option<NaiveDateTime>) -> anyhow::Result<u64> {
+    let mut tx = db
+        .begin()
+        .await
+        .map_err(|_| anyhow::anyhow!("Failed to begin transaction"))?;
+
+    let series_id = sqlx::query_scalar!(r#"SELECT id as "id!: i64" FROM series WHERE name = ?"#, series)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(anyhow::anyhow!("Series {series} not found"))?;
+
+    let from = from.map(|t| t.and_utc().timestamp_millis()).unwrap_or(i64::MIN);
+    let to = to.map(|t| t.and_utc().timestamp_millis()).unwrap_or(i64::MAX);
+    let result = sqlx::query!("DELETE FROM point WHERE series_id = ? AND time >= ? AND time < ?", series_id, from, to)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to delete points: {}", e))?;
+
+    if from == i64::MIN && to == i64::MAX {
+        sqlx::query!("DELETE FROM rollup WHERE series_id = ?", series_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to delete rollups: {}", e))?;
+        sqlx::query!("DELETE FROM series_metadata WHERE series_id = ?", series_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to delete metadata: {}", e))?;
+        sqlx::query!("DELETE FROM series_label WHERE series_id = ?", series_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to delete labels: {}", e))?;
+        sqlx::query!("DELETE FROM series WHERE id = ?", series_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to delete series: {}", e))?;
+    } else {
+        rebuild_rollups(&mut tx, series_id, from, to).await?;
+    }
+
+    tx.commit().await.map_err(|_| anyhow::anyhow!("Failed to commit transaction"))?;
+
+    Ok(result.rows_affected())
+}
+
+/// Moves the points of series `from` into series `into`, which is created if it doesn't exist,
+/// and deletes `from`. `into` keeps its metadata, or takes that of `from` if it has none. Returns
+/// how many points were moved.
+pub(crate) async fn merge_series(db: &mut sqlx::SqliteConnection, from: &str, into: &str) -> anyhow::Result<u64> {
+    if from == into {
+        return Err(anyhow::anyhow!("Cannot merge a series into itself"));
+    }
+
+    let mut tx = db
+        .begin()
+        .await
+        .map_err(|_| anyhow::anyhow!("Failed to begin transaction"))?;
+
+    let from_id = sqlx::query_scalar!(r#"SELECT id as "id!: i64" FROM series WHERE name = ?"#, from)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(anyhow::anyhow!("Series {from} not found"))?;
+
+    let inserted = sqlx::query!("INSERT OR IGNORE INTO series (name) VALUES (?)", into)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to insert series: {}", e))?;
+    let into_id = sqlx::query_scalar!(r#"SELECT id as "id!: i64" FROM series WHERE name = ?"#, into)
+        .fetch_one(&mut *tx)
+        .await?;
+    if inserted.rows_affected() == 1 {
+        set_labels(&mut tx, into_id, into).await?;
+    }
+
+    let result = sqlx::query!("UPDATE point SET series_id = ? WHERE series_id = ?", into_id, from_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to move points: {}", e))?;
+
+    sqlx::query!("DELETE FROM rollup WHERE series_id = ?", from_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to delete rollups: {}", e))?;
+    sqlx::query!("UPDATE OR IGNORE series_metadata SET series_id = ? WHERE series_id = ?", into_id, from_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to move metadata: {}", e))?;
+    sqlx::query!("DELETE FROM series_metadata WHERE series_id = ?", from_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to delete metadata: {}", e))?;
+    sqlx::query!("DELETE FROM series_label WHERE series_id = ?", from_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to delete labels: {}", e))?;
+    sqlx::query!("DELETE FROM series WHERE id = ?", from_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to delete series: {}", e))?;
+    rebuild_rollups(&mut tx, into_id, i64::MIN, i64::MAX).await?;
+
+    tx.commit().await.map_err(|_| anyhow::anyhow!("Failed to commit transaction"))?;
+
+    Ok(result.rows_affected())
+}
+
+pub(crate) async fn get_metadata(db: &mut sqlx::SqliteConnection, series: &str) -> anyhow::Result<SeriesMetadata> {
+    let metadata = sqlx::query_as!(
+            SeriesMetadata,
+            r#"
+            SELECT m.unit, m.description, m.decimals, m.min, m.max
+            FROM series_metadata m
+            JOIN series s ON s.id = m.series_id
+            WHERE s.name = ?
+            "#,
+            series
+        )
+        .fetch_optional(db)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to fetch metadata: {}", e))?;
+
+    Ok(metadata.unwrap_or_default())
+}
+
+/// Replaces the metadata of a series, creating the series if it doesn't exist
+pub(crate) async fn set_metadata(db: &mut sqlx::SqliteConnection, series: &str, metadata: &SeriesMetadata) -> anyhow::Result<()> {
+    let mut tx = db
+        .begin()
+        .await
+        .map_err(|_| anyhow::anyhow!("Failed to begin transaction"))?;
+
+    let inserted = sqlx::query!("INSERT OR IGNORE INTO series (name) VALUES (?)", series)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to insert series: {}", e))?;
+    let series_id = sqlx::query_scalar!(r#"SELECT id as "id!: i64" FROM series WHERE name = ?"#, series)
+        .fetch_one(&mut *tx)
+        .await?;
+    if inserted.rows_affected() == 1 {
+        set_labels(&mut tx, series_id, series).await?;
+    }
+
+    sqlx::query!(
+            r#"
+            INSERT OR REPLACE INTO series_metadata (series_id, unit, description, decimals, min, max)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+            series_id,
+            metadata.unit,
+            metadata.description,
+            metadata.decimals,
+            metadata.min,
+            metadata.max
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to set metadata: {}", e))?;
+
+    tx.commit().await.map_err(|_| anyhow::anyhow!("Failed to commit transaction"))?;
+
+    Ok(())
+}
+
+/// Recomputes the rollup buckets of a series which overlap `from`..`to`, in unix milliseconds, from
+/// the raw points after they were deleted or moved
+async fn rebuild_rollups(db: &mut sqlx::SqliteConnection, series_id: i64, from: i64, to: i64) -> anyhow::Result<()> {
+    for resolution in ROLLUP_RESOLUTIONS {
+        let bucket_millis = resolution * 1000;
+        let start = from.div_euclid(bucket_millis).saturating_mul(bucket_millis);
+        let end = to.saturating_add(bucket_millis - 1).div_euclid(bucket_millis).saturating_mul(bucket_millis);
+
+        sqlx::query!("
+                DELETE FROM rollup
+                WHERE series_id = $1 AND resolution = $2 AND time >= $3 AND time < $4
+            ",
+            series_id,
+            resolution,
+            start,
+            end
+            )
+            .execute(&mut *db)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to delete rollups: {}", e))?;
+
+        sqlx::query!("
+                WITH state AS (
+                    SELECT COALESCE((SELECT rolled_until FROM rollup_state WHERE resolution = $2), 0) AS rolled_until
+                ),
+                ranked AS (
+                    SELECT
+                        time / ($2 * 1000) * ($2 * 1000) AS bucket,
+                        time,
+                        value,
+                        ROW_NUMBER() OVER (PARTITION BY time / ($2 * 1000) ORDER BY time ASC, id ASC) AS rn_first,
+                        ROW_NUMBER() OVER (PARTITION BY time / ($2 * 1000) ORDER BY time DESC, id DESC) AS rn_last
+                    FROM point
+                    WHERE series_id = $1
+                        AND time >= $3
+                        AND time < $4
+                        AND time < (SELECT rolled_until FROM state)
+                )
+                INSERT INTO rollup (series_id, resolution, time, count, sum, min, max, first_time, first, last_time, last)
+                SELECT
+                    $1,
+                    $2,
+                    bucket,
+                    COUNT(*),
+                    SUM(value),
+                    MIN(value),
+                    MAX(value),
+                    MIN(time),
+                    MAX(CASE WHEN rn_first = 1 THEN value END),
+                    MAX(time),
+                    MAX(CASE WHEN rn_last = 1 THEN value END)
+                FROM ranked
+                GROUP BY bucket
+            ",
+            series_id,
+            resolution,
+            start,
+            end
+            )
+            .execute(&mut *db)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to rebuild rollups: {}", e))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sub_second_buckets() {
+        let mut db = sqlx::SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!("./migrations").run(&mut db).await.unwrap();
+
+        // Points every 50 ms over a minute are read in 118 ms buckets rather than whole seconds
+        let end = chrono::DateTime::from_timestamp(1_790_000_000, 0).unwrap().naive_utc();
+        let points = (0..1200)
+            .map(|i| ("vibration".to_string(), end - chrono::Duration::milliseconds(50 * i), i as f64))
+            .collect();
+        put_all(&mut db, points, &mut HashMap::new()).await.unwrap();
+
+        assert_eq!(bucket_millis(60, MAX_POINTS), 118);
+        let buckets = get(&mut db, "vibration", TimeRange{ end: Some(end), seconds: 60 }, MAX_POINTS).await.unwrap();
+        assert_eq!(buckets.len(), 509);
+        assert!(buckets.iter().all(|b| b.first - b.last <= 2.0));
+    }
+}
@@ -0,0 +1,137 @@
+use std::time::Duration;
+use regex::Regex;
+use tokio::process::Command;
+use crate::cli::validate_name;
+use crate::env::{Environment, ExecCollector};
+
+const DEFAULT_EXEC_INTERVAL_SECONDS: u64 = 60;
+
+/// Periodically runs a shell command and stores the numbers found in its output
+pub(crate) async fn run(config: ExecCollector, env: Environment) -> anyhow::Result<()> {
+    validate_name(&config.series).map_err(|e| anyhow::anyhow!("Invalid series name '{}': {e}", config.series))?;
+    let interval = Duration::from_secs(config.interval.unwrap_or(DEFAULT_EXEC_INTERVAL_SECONDS).max(1));
+    let parser = match &config.pattern {
+        Some(pattern) => OutputParser::Pattern(Regex::new(pattern)?),
+        None => OutputParser::Lines,
+    };
+    let failed_series = format!("{}_exec_failed", config.series);
+    println!("Running '{}' every {}s", config.command, interval.as_secs());
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let now = chrono::Utc::now().naive_utc();
+
+        let mut points = match execute(&config.command, interval).await.and_then(|out| parser.parse(&config.series, &out)) {
+            Ok(values) => values.into_iter().map(|(series, value)| (series, now, value)).collect(),
+            Err(e) => {
+                println!("Command '{}' failed: {e}", config.command);
+                vec![]
+            }
+        };
+        let failed = if points.is_empty() { 1.0 } else { 0.0 };
+        points.push((failed_series.clone(), now, failed));
+
+        let result = env.db.put_all(points).await;
+        if let Err(e) = result {
+            println!("Error while writing output of '{}': {e}", config.command);
+        }
+    }
+}
+
+async fn execute(command: &str, timeout: Duration) -> Result<String, String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .kill_on_drop(true)
+        .output();
+
+    let output = tokio::time::timeout(timeout, output)
+        .await
+        .map_err(|_| format!("Timed out after {}s", timeout.as_secs()))?
+        .map_err(|e| e.to_string())?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{}: {}", output.status, stderr.trim()));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+pub(crate) enum OutputParser {
+    /// The first match of the pattern. Each named capture group is stored to `<series>_<group name>`,
+    /// otherwise the first capture group, or the whole match if there are no groups, is stored to `<series>`.
+    Pattern(Regex),
+    /// One number per line. A single line is stored to `<series>`, otherwise line n is stored to `<series>_<n>`.
+    Lines,
+}
+
+impl OutputParser {
+    pub fn parse(&self, series: &str, output: &str) -> Result<Vec<(String, f64)>, String> {
+        let parse_number = |text: &str| {
+            text.trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|v| v.is_finite())
+                .ok_or(format!("Not a number: '{}'", text.trim()))
+        };
+
+        match self {
+            OutputParser::Pattern(regex) => {
+                let captures = regex.captures(output).ok_or("Pattern didn't match the output")?;
+                let named = regex
+                    .capture_names()
+                    .flatten()
+                    .filter_map(|name| Some((name, captures.name(name)?)))
+                    .map(|(name, m)| Ok((format!("{series}_{name}"), parse_number(m.as_str())?)))
+                    .collect::<Result<Vec<_>, String>>()?;
+                if !named.is_empty() {
+                    return Ok(named);
+                }
+                let m = captures.get(1).or(captures.get(0)).ok_or("Pattern didn't match the output")?;
+                Ok(vec![(series.to_string(), parse_number(m.as_str())?)])
+            },
+            OutputParser::Lines => {
+                let lines = output.lines().filter(|l| !l.trim().is_empty()).collect::<Vec<_>>();
+                match lines.as_slice() {
+                    [] => Err("No output".to_string()),
+                    [line] => Ok(vec![(series.to_string(), parse_number(line)?)]),
+                    lines => lines
+                        .iter()
+                        .enumerate()
+                        .map(|(i, line)| Ok((format!("{series}_{}", i + 1), parse_number(line)?)))
+                        .collect(),
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_output() {
+        let pattern = OutputParser::Pattern(Regex::new(r"temp=([0-9.]+)'C").unwrap());
+        assert_eq!(pattern.parse("cpu_temp_c", "temp=48.3'C\n").unwrap(), vec![("cpu_temp_c".to_string(), 48.3)]);
+        assert!(pattern.parse("cpu_temp_c", "error\n").is_err());
+
+        let named = OutputParser::Pattern(Regex::new(r"(?P<used>\d+) used, (?P<free>\d+) free").unwrap());
+        assert_eq!(named.parse("swap", "10 used, 90 free").unwrap(), vec![("swap_used".to_string(), 10.0), ("swap_free".to_string(), 90.0)]);
+
+        let lines = OutputParser::Lines;
+        assert_eq!(lines.parse("fan_rpm", " 1200 \n").unwrap(), vec![("fan_rpm".to_string(), 1200.0)]);
+        assert_eq!(lines.parse("fan_rpm", "1200\n\n900\n").unwrap(), vec![("fan_rpm_1".to_string(), 1200.0), ("fan_rpm_2".to_string(), 900.0)]);
+        assert!(lines.parse("fan_rpm", "").is_err());
+        assert!(lines.parse("fan_rpm", "1200\nfast\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_execute() {
+        assert_eq!(execute("echo 42", Duration::from_secs(5)).await.unwrap(), "42\n");
+        assert!(execute("exit 3", Duration::from_secs(5)).await.is_err());
+        assert!(execute("sleep 5", Duration::from_millis(100)).await.is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use chrono::SecondsFormat;
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use serde::Deserialize;
+use crate::labels::Selector;
+use crate::model::Point;
+use crate::storage::Storage;
+
+const PARQUET_SCHEMA: &str = "
+    message point {
+        REQUIRED BYTE_ARRAY series (STRING);
+        REQUIRED INT64 time (TIMESTAMP(MILLIS,true));
+        REQUIRED DOUBLE value;
+    }
+";
+
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ExportFormat {
+    /// Rows of series, time and value in the format read by `push-all`
+    #[default]
+    Csv,
+    /// One JSON object with series, time and value per line
+    Jsonl,
+    /// A Parquet file with series, time and value columns
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Jsonl => "application/jsonl",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+/// Why the series to export couldn't be found
+#[derive(Debug)]
+pub(crate) enum ResolveError {
+    /// A pattern isn't a valid selector
+    Invalid(String),
+    /// A pattern matches no series
+    NoMatch(String),
+    Storage(anyhow::Error),
+}
+
+impl std::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResolveError::Invalid(message) => write!(f, "{message}"),
+            ResolveError::NoMatch(pattern) => write!(f, "No series matches {pattern}"),
+            ResolveError::Storage(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+/// Returns the series matching any of `patterns`, which are series names, globs or label
+/// selectors, in the order they are first matched
+pub(crate) async fn resolve(db: &dyn Storage, patterns: &[String]) -> Result<Vec<String>, ResolveError> {
+    let mut series: Vec<String> = vec![];
+    for pattern in patterns {
+        let selector = Selector::parse(pattern).map_err(|e| ResolveError::Invalid(format!("Invalid selector {pattern}: {e}")))?;
+        let matched = selector.resolve(db).await.map_err(ResolveError::Storage)?;
+        if matched.is_empty() {
+            return Err(ResolveError::NoMatch(pattern.clone()));
+        }
+        for name in matched {
+            if !series.contains(&name) {
+                series.push(name);
+            }
+        }
+    }
+    Ok(series)
+}
+
+/// Writes points in an export format a series at a time, so that only one series needs to be read
+/// into memory at once
+pub(crate) enum Exporter<W: Write + Send> {
+    Csv(W),
+    Jsonl(W),
+    /// Writes one row group per series
+    Parquet(SerializedFileWriter<W>),
+}
+
+impl<W: Write + Send> Exporter<W> {
+    pub fn new(format: ExportFormat, out: W) -> anyhow::Result<Self> {
+        Ok(match format {
+            ExportFormat::Csv => Exporter::Csv(out),
+            ExportFormat::Jsonl => Exporter::Jsonl(out),
+            ExportFormat::Parquet => {
+                let schema = Arc::new(parse_message_type(PARQUET_SCHEMA)?);
+                let properties = Arc::new(WriterProperties::builder().set_compression(Compression::SNAPPY).build());
+                Exporter::Parquet(SerializedFileWriter::new(out, schema, properties)?)
+            },
+        })
+    }
+
+    pub fn write_series(&mut self, name: &str, points: &[Point]) -> anyhow::Result<()> {
+        match self {
+            Exporter::Csv(out) => write_csv(name, points, out),
+            Exporter::Jsonl(out) => write_jsonl(name, points, out),
+            Exporter::Parquet(writer) => write_parquet(name, points, writer),
+        }
+    }
+
+    /// Writes what remains, such as the Parquet footer, and flushes the output
+    pub fn finish(self) -> anyhow::Result<()> {
+        match self {
+            Exporter::Csv(mut out) | Exporter::Jsonl(mut out) => out.flush()?,
+            Exporter::Parquet(writer) => writer.into_inner()?.flush()?,
+        }
+        Ok(())
+    }
+}
+
+fn write_csv<W: Write>(name: &str, points: &[Point], out: &mut W) -> anyhow::Result<()> {
+    // Quote series whose labels contain commas or quotes
+    let name = match name.contains([',', '"']) {
+        true => format!("\"{}\"", name.replace('"', "\"\"")),
+        false => name.to_string(),
+    };
+    for point in points {
+        writeln!(out, "{name},{},{}", point.time.format("%Y-%m-%d %H:%M:%S%.f"), point.value)?;
+    }
+    Ok(())
+}
+
+fn write_jsonl<W: Write>(name: &str, points: &[Point], out: &mut W) -> anyhow::Result<()> {
+    for point in points {
+        let row = serde_json::json!({
+            "series": name,
+            "time": point.time.and_utc().to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            "value": point.value,
+        });
+        writeln!(out, "{row}")?;
+    }
+    Ok(())
+}
+
+fn write_parquet<W: Write + Send>(name: &str, points: &[Point], writer: &mut SerializedFileWriter<W>) -> anyhow::Result<()> {
+    if points.is_empty() {
+        return Ok(());
+    }
+    let names = vec![ByteArray::from(name); points.len()];
+    let times: Vec<_> = points.iter().map(|p| p.time.and_utc().timestamp_millis()).collect();
+    let values: Vec<_> = points.iter().map(|p| p.value).collect();
+
+    let mut row_group = writer.next_row_group()?;
+    let mut column = row_group.next_column()?.ok_or(anyhow::anyhow!("Missing series column"))?;
+    column.typed::<ByteArrayType>().write_batch(&names, None, None)?;
+    column.close()?;
+    let mut column = row_group.next_column()?.ok_or(anyhow::anyhow!("Missing time column"))?;
+    column.typed::<Int64Type>().write_batch(&times, None, None)?;
+    column.close()?;
+    let mut column = row_group.next_column()?.ok_or(anyhow::anyhow!("Missing value column"))?;
+    column.typed::<DoubleType>().write_batch(&values, None, None)?;
+    column.close()?;
+    row_group.close()?;
+    Ok(())
+}
+
+/// An output which collects what is written so it can be taken a chunk at a time, such as after
+/// each series when streaming a download
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    /// Returns what has been written since the last call
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    /// Writes the series a series at a time, as an export does
+    fn write(format: ExportFormat, series: &[(String, Vec<Point>)]) -> Vec<u8> {
+        let out = SharedBuffer::default();
+        let mut exporter = Exporter::new(format, out.clone()).unwrap();
+        for (name, points) in series {
+            exporter.write_series(name, points).unwrap();
+        }
+        exporter.finish().unwrap();
+        out.take()
+    }
+
+    #[test]
+    fn test_write() {
+        let time = NaiveDateTime::parse_from_str("2024-06-13 15:30:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let series = vec![
+            ("solar_power".to_string(), vec![Point{ time, value: 32.14 }, Point{ time: time + chrono::Duration::minutes(1), value: 0.0 }]),
+            ("temperature".to_string(), vec![Point{ time, value: -2.5 }]),
+        ];
+
+        assert_eq!(String::from_utf8(write(ExportFormat::Csv, &series)).unwrap(), "\
+solar_power,2024-06-13 15:30:00,32.14
+solar_power,2024-06-13 15:31:00,0
+temperature,2024-06-13 15:30:00,-2.5
+");
+
+        assert_eq!(String::from_utf8(write(ExportFormat::Jsonl, &series[1..])).unwrap(), "{\"series\":\"temperature\",\"time\":\"2024-06-13T15:30:00Z\",\"value\":-2.5}\n");
+
+        let reader = SerializedFileReader::new(axum::body::Bytes::from(write(ExportFormat::Parquet, &series))).unwrap();
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+        let rows: Vec<_> = reader.get_row_iter(None).unwrap().map(|row| row.unwrap().to_string()).collect();
+        assert_eq!(rows[0], "{series: \"solar_power\", time: 2024-06-13 15:30:00.000 +00:00, value: 32.14}");
+        assert_eq!(rows.len(), 3);
+    }
+}
@@ -0,0 +1,516 @@
+use std::collections::{BTreeMap, HashMap};
+use regex::Regex;
+use crate::db;
+use crate::labels::Selector;
+use crate::model::{Bucket, TimeRange};
+use crate::storage::Storage;
+
+/// A series derived from stored series, e.g. `f32_mppt_a_w + f32_mppt_c_w`. Widgets accept an
+/// expression wherever they take a series, a plain series name or selector being the simplest.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Expr {
+    Number(f64),
+    /// A series name, glob or label selector
+    Series(String),
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Function {
+    /// Change per second from the previous bucket
+    Rate,
+    /// Change from the previous bucket
+    Delta,
+    /// Running total of value × seconds over the range
+    Integral,
+    /// Average over a trailing window of seconds
+    MovingAvg,
+    Clamp,
+    Abs,
+    /// Combine the series a selector matches into one, bucket by bucket
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+impl Function {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "rate" => Function::Rate,
+            "delta" => Function::Delta,
+            "integral" => Function::Integral,
+            "moving_avg" => Function::MovingAvg,
+            "clamp" => Function::Clamp,
+            "abs" => Function::Abs,
+            "sum" => Function::Sum,
+            "avg" => Function::Avg,
+            "min" => Function::Min,
+            "max" => Function::Max,
+            _ => return None,
+        })
+    }
+
+    fn arguments(&self) -> usize {
+        match self {
+            Function::MovingAvg => 2,
+            Function::Clamp => 3,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Word(String),
+    Op(char),
+    Open,
+    Close,
+    Comma,
+}
+
+/// Splits an expression into tokens. Series names may contain `-` and globs `*`, so operators
+/// must be separated from the names before them by a space.
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let duration = Regex::new(r"^(\d+(?:\.\d+)?)(ms|s|m|h|d|w)$").unwrap();
+    let mut chars = text.chars().peekable();
+    let mut tokens = vec![];
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let token = match c {
+            '(' => Token::Open,
+            ')' => Token::Close,
+            ',' => Token::Comma,
+            '+' | '-' | '*' | '/' => Token::Op(c),
+            c if c.is_ascii_alphanumeric() || c == '_' || c == '.' => {
+                let mut word: String = std::iter::from_fn(|| chars.next_if(|c| c.is_ascii_alphanumeric() || "_-*?.".contains(*c))).collect();
+                if chars.peek() == Some(&'{') {
+                    let mut quote = None;
+                    for c in chars.by_ref() {
+                        word.push(c);
+                        match (quote, c) {
+                            (None, '"' | '\'') => quote = Some(c),
+                            (Some(q), c) if c == q => quote = None,
+                            (None, '}') => break,
+                            _ => {}
+                        }
+                    }
+                    if quote.is_some() || !word.ends_with('}') {
+                        return Err(format!("Missing }} in {word}"));
+                    }
+                }
+                let starts_with_number = word.starts_with(|c: char| c.is_ascii_digit() || c == '.');
+                if let (true, Ok(number)) = (starts_with_number, word.parse::<f64>()) {
+                    tokens.push(Token::Number(number));
+                } else if let Some(captures) = duration.captures(&word) {
+                    let value: f64 = captures[1].parse().map_err(|_| format!("Invalid duration {word}"))?;
+                    let unit = match &captures[2] {
+                        "ms" => 0.001,
+                        "s" => 1.0,
+                        "m" => 60.0,
+                        "h" => 3600.0,
+                        "d" => 86400.0,
+                        _ => 604800.0,
+                    };
+                    tokens.push(Token::Number(value * unit));
+                } else {
+                    tokens.push(Token::Word(word));
+                }
+                continue;
+            },
+            c => return Err(format!("Unexpected {c}")),
+        };
+        chars.next();
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token, what: &str) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            _ => Err(format!("Expected {what}")),
+        }
+    }
+
+    /// expression = term { ("+" | "-") term }
+    fn expression(&mut self) -> Result<Expr, String> {
+        let mut left = self.term()?;
+        while let Some(Token::Op(c @ ('+' | '-'))) = self.peek() {
+            let op = if *c == '+' { Op::Add } else { Op::Sub };
+            self.next();
+            left = Expr::Binary(op, Box::new(left), Box::new(self.term()?));
+        }
+        Ok(left)
+    }
+
+    /// term = unary { ("*" | "/") unary }
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        while let Some(Token::Op(c @ ('*' | '/'))) = self.peek() {
+            let op = if *c == '*' { Op::Mul } else { Op::Div };
+            self.next();
+            left = Expr::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    /// unary = "-" unary | number | function "(" arguments ")" | series | "(" expression ")"
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Op('-')) => Ok(Expr::Neg(Box::new(self.unary()?))),
+            Some(Token::Number(number)) => Ok(Expr::Number(number)),
+            Some(Token::Open) => {
+                let expr = self.expression()?;
+                self.expect(Token::Close, ")")?;
+                Ok(expr)
+            },
+            Some(Token::Word(word)) if self.peek() == Some(&Token::Open) => {
+                let function = Function::parse(&word).ok_or(format!("Unknown function {word}"))?;
+                self.next();
+                let mut arguments = vec![self.expression()?];
+                while self.peek() == Some(&Token::Comma) {
+                    self.next();
+                    arguments.push(self.expression()?);
+                }
+                self.expect(Token::Close, ")")?;
+                if arguments.len() != function.arguments() {
+                    return Err(format!("{word}() takes {} arguments", function.arguments()));
+                }
+                Ok(Expr::Call(function, arguments))
+            },
+            Some(Token::Word(word)) => {
+                Selector::parse(&word).map_err(|e| format!("Invalid series {word}: {e}"))?;
+                Ok(Expr::Series(word))
+            },
+            _ => Err("Expected a number, series or function".to_string()),
+        }
+    }
+}
+
+/// The values of a series by bucket number
+type Values = BTreeMap<i64, f64>;
+
+enum Value {
+    Scalar(f64),
+    /// Any number of named series
+    Vector(Vec<(String, Values)>),
+}
+
+/// The points of the series an expression reads, by selector
+type Data = HashMap<String, Vec<(String, Vec<Bucket>)>>;
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parser = Parser{ tokens: tokenize(text)?, position: 0 };
+        let expr = parser.expression()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(_) => Err("Unexpected text after the expression, separate operators from series names with spaces".to_string()),
+        }
+    }
+
+    /// The selector if the expression is just a series
+    pub fn selector(&self) -> Option<&str> {
+        match self {
+            Expr::Series(selector) => Some(selector),
+            _ => None,
+        }
+    }
+
+    /// The selectors of the series the expression reads
+    pub fn selectors(&self) -> Vec<&str> {
+        match self {
+            Expr::Number(_) => vec![],
+            Expr::Series(selector) => vec![selector],
+            Expr::Neg(expr) => expr.selectors(),
+            Expr::Binary(_, left, right) => [left.selectors(), right.selectors()].concat(),
+            Expr::Call(_, arguments) => arguments.iter().flat_map(Expr::selectors).collect(),
+        }
+    }
+
+    /// Returns the buckets of each series the expression gives over the range, like `db::get`
+    /// returns them for a stored series. Each field of a bucket is the expression evaluated on
+    /// that field of the buckets read, so an envelope shows the expression of the minimums and
+    /// maximums.
+    pub async fn get(&self, db: &dyn Storage, range: TimeRange, buckets: i64) -> anyhow::Result<Vec<(String, Vec<Bucket>)>> {
+        let mut data = Data::new();
+        for selector in self.selectors() {
+            if data.contains_key(selector) {
+                continue;
+            }
+            let names = Selector::parse(selector)
+                .map_err(|e| anyhow::anyhow!("Invalid series selector {selector}: {e}"))?
+                .resolve(db)
+                .await?;
+            let mut series = vec![];
+            for name in names {
+                let buckets = db.get(&name, range, buckets).await?;
+                series.push((name, buckets));
+            }
+            data.insert(selector.to_string(), series);
+        }
+
+        if let Some(selector) = self.selector() {
+            return Ok(data.remove(selector).unwrap_or_default());
+        }
+
+        let width = db::bucket_millis(range.seconds, buckets);
+        let evaluate = |field: fn(&Bucket) -> f64| -> anyhow::Result<Vec<(String, Values)>> {
+            match self.evaluate(&data, field, width).map_err(|e| anyhow::anyhow!(e))? {
+                Value::Vector(series) => Ok(series),
+                // A constant is drawn across the range
+                Value::Scalar(value) => {
+                    let end = range.end().and_utc().timestamp_millis() / width;
+                    let start = range.start().and_utc().timestamp_millis() / width;
+                    Ok(vec![("constant".to_string(), (start..=end).map(|bucket| (bucket, value)).collect())])
+                },
+            }
+        };
+        let first = evaluate(|b| b.first)?;
+        let last = evaluate(|b| b.last)?;
+        let min = evaluate(|b| b.min)?;
+        let max = evaluate(|b| b.max)?;
+        let avg = evaluate(|b| b.avg)?;
+
+        let mut result = vec![];
+        for (i, (name, first)) in first.into_iter().enumerate() {
+            let buckets = first
+                .into_iter()
+                .filter_map(|(bucket, first)| {
+                    let (low, high) = (*min[i].1.get(&bucket)?, *max[i].1.get(&bucket)?);
+                    Some(Bucket{
+                        time: chrono::DateTime::from_timestamp_millis(bucket * width)?.naive_utc(),
+                        first,
+                        last: *last[i].1.get(&bucket)?,
+                        // A negated minimum is the maximum
+                        min: low.min(high),
+                        max: low.max(high),
+                        avg: *avg[i].1.get(&bucket)?,
+                    })
+                })
+                .collect();
+            result.push((name, buckets));
+        }
+        Ok(result)
+    }
+
+    fn evaluate(&self, data: &Data, field: fn(&Bucket) -> f64, width: i64) -> Result<Value, String> {
+        match self {
+            Expr::Number(number) => Ok(Value::Scalar(*number)),
+            Expr::Series(selector) => Ok(Value::Vector(
+                data.get(selector)
+                    .into_iter()
+                    .flatten()
+                    .map(|(name, buckets)| {
+                        let values = buckets.iter().map(|b| (b.time.and_utc().timestamp_millis() / width, field(b))).collect();
+                        (name.clone(), values)
+                    })
+                    .collect()
+            )),
+            Expr::Neg(expr) => Ok(map(expr.evaluate(data, field, width)?, |v| -v)),
+            Expr::Binary(op, left, right) => {
+                let left = left.evaluate(data, field, width)?;
+                let right = right.evaluate(data, field, width)?;
+                let f = match op {
+                    Op::Add => |a: f64, b: f64| a + b,
+                    Op::Sub => |a, b| a - b,
+                    Op::Mul => |a, b| a * b,
+                    Op::Div => |a, b| a / b,
+                };
+                combine(left, right, f)
+            },
+            Expr::Call(function, arguments) => {
+                let mut values = arguments
+                    .iter()
+                    .map(|argument| argument.evaluate(data, field, width))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .into_iter();
+                let series = values.next().unwrap();
+                let mut scalar = |name: &str| match values.next() {
+                    Some(Value::Scalar(value)) => Ok(value),
+                    _ => Err(format!("The {name} must be a number")),
+                };
+                let seconds = width as f64 / 1000.0;
+                match function {
+                    Function::Rate => per_series(series, "rate", |values| changes(values).map(|(bucket, change, buckets)| (bucket, change / (buckets as f64 * seconds))).collect()),
+                    Function::Delta => per_series(series, "delta", |values| changes(values).map(|(bucket, change, _)| (bucket, change)).collect()),
+                    Function::Integral => per_series(series, "integral", |values| {
+                        let mut total = 0.0;
+                        values.iter().map(|(bucket, value)| { total += value * seconds; (*bucket, total) }).collect()
+                    }),
+                    Function::MovingAvg => {
+                        let window = scalar("window of moving_avg()")?;
+                        let buckets = ((window / seconds).round() as i64).max(1);
+                        per_series(series, "moving_avg", |values| {
+                            values
+                                .keys()
+                                .map(|bucket| {
+                                    let window: Vec<_> = values.range(bucket - buckets + 1..=*bucket).map(|(_, v)| *v).collect();
+                                    (*bucket, window.iter().sum::<f64>() / window.len() as f64)
+                                })
+                                .collect()
+                        })
+                    },
+                    Function::Clamp => {
+                        let (low, high) = (scalar("minimum of clamp()")?, scalar("maximum of clamp()")?);
+                        if low > high {
+                            return Err("The minimum of clamp() must not be more than the maximum".to_string());
+                        }
+                        Ok(map(series, |v| v.clamp(low, high)))
+                    },
+                    Function::Abs => Ok(map(series, f64::abs)),
+                    Function::Sum => Ok(aggregate(series, "sum", |values| values.iter().sum())),
+                    Function::Avg => Ok(aggregate(series, "avg", |values| values.iter().sum::<f64>() / values.len() as f64)),
+                    Function::Min => Ok(aggregate(series, "min", |values| values.iter().copied().fold(f64::INFINITY, f64::min))),
+                    Function::Max => Ok(aggregate(series, "max", |values| values.iter().copied().fold(f64::NEG_INFINITY, f64::max))),
+                }
+            },
+        }
+    }
+}
+
+fn map(value: Value, f: impl Fn(f64) -> f64) -> Value {
+    match value {
+        Value::Scalar(value) => Value::Scalar(f(value)),
+        Value::Vector(series) => Value::Vector(
+            series
+                .into_iter()
+                .map(|(name, values)| (name, values.into_iter().map(|(bucket, v)| (bucket, f(v))).filter(|(_, v)| v.is_finite()).collect()))
+                .collect()
+        ),
+    }
+}
+
+/// Applies an operator bucket by bucket. A single series is combined with each series on the
+/// other side, and only buckets which both series have give a value.
+fn combine(left: Value, right: Value, f: fn(f64, f64) -> f64) -> Result<Value, String> {
+    let join = |a: &Values, b: &Values| -> Values {
+        a.iter()
+            .filter_map(|(bucket, x)| b.get(bucket).map(|y| (*bucket, f(*x, *y))))
+            .filter(|(_, v)| v.is_finite())
+            .collect()
+    };
+    match (left, right) {
+        (Value::Scalar(x), Value::Scalar(y)) => Ok(Value::Scalar(f(x, y))),
+        (Value::Scalar(x), right) => Ok(map(right, |y| f(x, y))),
+        (left, Value::Scalar(y)) => Ok(map(left, |x| f(x, y))),
+        (Value::Vector(left), Value::Vector(right)) => match (left.as_slice(), right.as_slice()) {
+            ([], _) | (_, []) => Ok(Value::Vector(vec![])),
+            (left, [(_, b)]) => Ok(Value::Vector(left.iter().map(|(name, a)| (name.clone(), join(a, b))).collect())),
+            ([(_, a)], right) => Ok(Value::Vector(right.iter().map(|(name, b)| (name.clone(), join(a, b))).collect())),
+            _ => Err("Both sides of an operator match several series, combine them with sum(), avg(), min() or max()".to_string()),
+        },
+    }
+}
+
+fn per_series(value: Value, name: &str, f: impl Fn(&Values) -> Values) -> Result<Value, String> {
+    match value {
+        Value::Scalar(_) => Err(format!("{name}() needs a series")),
+        Value::Vector(series) => Ok(Value::Vector(series.into_iter().map(|(name, values)| (name, f(&values))).collect())),
+    }
+}
+
+/// The change of each value from the previous one, with the number of buckets between them
+fn changes(values: &Values) -> impl Iterator<Item = (i64, f64, i64)> + '_ {
+    values
+        .iter()
+        .zip(values.iter().skip(1))
+        .map(|((previous, a), (bucket, b))| (*bucket, b - a, bucket - previous))
+}
+
+/// Combines all series into one, bucket by bucket, from the series which have a value in each bucket
+fn aggregate(value: Value, name: &str, f: fn(&[f64]) -> f64) -> Value {
+    match value {
+        Value::Scalar(value) => Value::Scalar(value),
+        Value::Vector(series) => {
+            let mut buckets: BTreeMap<i64, Vec<f64>> = BTreeMap::new();
+            for (_, values) in &series {
+                for (bucket, value) in values {
+                    buckets.entry(*bucket).or_default().push(*value);
+                }
+            }
+            let values = buckets.iter().map(|(bucket, values)| (*bucket, f(values))).collect();
+            Value::Vector(vec![(name.to_string(), values)])
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expressions() {
+        let series = |s: &str| Box::new(Expr::Series(s.to_string()));
+        assert_eq!(Expr::parse("f32_mppt_a_w + f32_mppt_c_w").unwrap(), Expr::Binary(Op::Add, series("f32_mppt_a_w"), series("f32_mppt_c_w")));
+        assert_eq!(Expr::parse("battery-v * battery-a").unwrap(), Expr::Binary(Op::Mul, series("battery-v"), series("battery-a")));
+        assert_eq!(Expr::parse("-x / 2 + 1").unwrap(), Expr::Binary(Op::Add, Box::new(Expr::Binary(Op::Div, Box::new(Expr::Neg(series("x"))), Box::new(Expr::Number(2.0)))), Box::new(Expr::Number(1.0))));
+        assert_eq!(Expr::parse("moving_avg(x, 10m)").unwrap(), Expr::Call(Function::MovingAvg, vec![*series("x"), Expr::Number(600.0)]));
+        assert_eq!(Expr::parse("sum(panel_power_w{mppt=~'a|c'})").unwrap().selectors(), vec!["panel_power_w{mppt=~'a|c'}"]);
+        assert!(Expr::parse("clamp(x, 0)").is_err());
+        assert!(Expr::parse("x +").is_err());
+        assert!(Expr::parse("median(x)").is_err());
+
+        let time = |minutes: i64| chrono::DateTime::from_timestamp(minutes * 60, 0).unwrap().naive_utc();
+        let bucket = |minutes: i64, value: f64| Bucket{ time: time(minutes), first: value, last: value, min: value, max: value, avg: value };
+        let data: Data = HashMap::from([
+            ("a".to_string(), vec![("a".to_string(), vec![bucket(0, 1.0), bucket(1, 2.0), bucket(2, 4.0)])]),
+            ("b{}".to_string(), vec![("b{x=\"1\"}".to_string(), vec![bucket(1, 10.0)]), ("b{x=\"2\"}".to_string(), vec![bucket(2, 20.0)])]),
+        ]);
+        let evaluate = |text: &str| match Expr::parse(text).unwrap().evaluate(&data, |b| b.avg, 60_000) {
+            Ok(Value::Vector(series)) => Ok(series.into_iter().map(|(_, values)| values.into_values().collect::<Vec<_>>()).collect::<Vec<_>>()),
+            Ok(Value::Scalar(value)) => Ok(vec![vec![value]]),
+            Err(e) => Err(e),
+        };
+        assert_eq!(evaluate("a * 2 + 1"), Ok(vec![vec![3.0, 5.0, 9.0]]));
+        assert_eq!(evaluate("a + b{}"), Ok(vec![vec![12.0], vec![24.0]]));
+        assert_eq!(evaluate("sum(b{}) - a"), Ok(vec![vec![8.0, 16.0]]));
+        assert_eq!(evaluate("delta(a)"), Ok(vec![vec![1.0, 2.0]]));
+        assert_eq!(evaluate("rate(a)"), Ok(vec![vec![1.0 / 60.0, 2.0 / 60.0]]));
+        assert_eq!(evaluate("integral(a) / 1m"), Ok(vec![vec![1.0, 3.0, 7.0]]));
+        assert_eq!(evaluate("moving_avg(a, 2m)"), Ok(vec![vec![1.0, 1.5, 3.0]]));
+        assert_eq!(evaluate("clamp(a, 1.5, 3)"), Ok(vec![vec![1.5, 2.0, 3.0]]));
+        assert_eq!(evaluate("a / (a - 2)"), Ok(vec![vec![-1.0, 2.0]]));
+        assert!(evaluate("b{} * b{}").is_err());
+
+        // Combining two single series keeps the name of the left one
+        let names = |text: &str| match Expr::parse(text).unwrap().evaluate(&data, |b| b.avg, 60_000) {
+            Ok(Value::Vector(series)) => series.into_iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            _ => vec![],
+        };
+        assert_eq!(names("a - sum(b{})"), vec!["a"]);
+        assert_eq!(names("sum(b{}) - a"), vec!["sum"]);
+        assert!(evaluate("rate(5)").is_err());
+    }
+}
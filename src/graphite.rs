@@ -0,0 +1,118 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use chrono::{DateTime, NaiveDateTime};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use crate::env::Environment;
+use crate::model::to_series_name;
+
+/// Points received on a connection are written once this many have been buffered..
+const MAX_BUFFERED_POINTS: usize = 500;
+/// ..or when the connection has been idle for this long
+const FLUSH_AFTER: Duration = Duration::from_secs(1);
+
+/// Receives metrics in the Graphite plaintext protocol over TCP
+pub(crate) async fn listen(addr: SocketAddr, env: Environment) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    println!("Graphite: TCP {addr}");
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let env = env.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, env).await {
+                println!("Graphite connection from {peer} failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle(stream: TcpStream, env: Environment) -> anyhow::Result<()> {
+    let mut lines = BufReader::new(stream).lines();
+    let mut points = vec![];
+
+    loop {
+        let line = match tokio::time::timeout(FLUSH_AFTER, lines.next_line()).await {
+            Ok(line) => line?,
+            Err(_) => {
+                write(&env, &mut points).await?;
+                continue;
+            }
+        };
+
+        let Some(line) = line else {
+            break;
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match parse_line(&line, chrono::Utc::now().naive_utc()) {
+            Ok(point) => points.push(point),
+            Err(e) => println!("Ignoring Graphite line '{line}': {e}"),
+        }
+
+        if points.len() >= MAX_BUFFERED_POINTS {
+            write(&env, &mut points).await?;
+        }
+    }
+
+    write(&env, &mut points).await
+}
+
+async fn write(env: &Environment, points: &mut Vec<(String, NaiveDateTime, f64)>) -> anyhow::Result<()> {
+    if points.is_empty() {
+        return Ok(());
+    }
+    env.db.put_all(std::mem::take(points)).await
+}
+
+/// Parses a line of the form `path value [timestamp]`. The dotted path is mapped to a series name
+/// by replacing dots and any other invalid characters with underscores. A missing timestamp or a
+/// timestamp of -1 means now.
+pub(crate) fn parse_line(line: &str, now: NaiveDateTime) -> Result<(String, NaiveDateTime, f64), String> {
+    let parts = line.split_whitespace().collect::<Vec<_>>();
+    let (path, value, timestamp) = match parts.as_slice() {
+        [path, value] => (*path, *value, None),
+        [path, value, timestamp] => (*path, *value, Some(*timestamp)),
+        _ => return Err("Expected: path value [timestamp]".to_string()),
+    };
+
+    let series = to_series_name(path).ok_or(format!("Invalid path: {path}"))?;
+
+    let value = value
+        .parse::<f64>()
+        .ok()
+        .filter(|v| v.is_finite())
+        .ok_or(format!("Invalid value: {value}"))?;
+
+    let time = match timestamp.map(|t| t.parse::<f64>()) {
+        None => now,
+        Some(Ok(-1.0)) => now,
+        Some(Ok(t)) => DateTime::from_timestamp_millis((t * 1000.0).round() as i64)
+            .ok_or(format!("Invalid timestamp: {t}"))?
+            .naive_utc(),
+        Some(Err(_)) => return Err(format!("Invalid timestamp: {}", timestamp.unwrap_or_default())),
+    };
+
+    Ok((series, time, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        let now = DateTime::from_timestamp(1_718_292_600, 0).unwrap().naive_utc();
+        let then = DateTime::from_timestamp(1_718_289_000, 0).unwrap().naive_utc();
+
+        assert_eq!(parse_line("servers.Pi-4.cpu.temp 41.5 1718289000", now).unwrap(), ("servers_pi-4_cpu_temp".to_string(), then, 41.5));
+        assert_eq!(parse_line("solar.power 120 -1", now).unwrap(), ("solar_power".to_string(), now, 120.0));
+        assert_eq!(parse_line("solar.power 120", now).unwrap(), ("solar_power".to_string(), now, 120.0));
+        assert!(parse_line("solar.power", now).is_err());
+        assert!(parse_line("solar.power abc 1718289000", now).is_err());
+        assert!(parse_line("... 1 1718289000", now).is_err());
+    }
+}
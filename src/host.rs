@@ -0,0 +1,184 @@
+use std::path::Path;
+use std::time::Duration;
+use crate::env::{Environment, HostCollector, HostMetric};
+
+const DEFAULT_HOST_INTERVAL_SECONDS: u64 = 60;
+const THERMAL_ZONE: &str = "/sys/class/thermal/thermal_zone0/temp";
+
+/// Periodically samples metrics of the host that the server runs on from `/proc` and `/sys`
+pub(crate) async fn run(config: HostCollector, env: Environment) -> anyhow::Result<()> {
+    let interval = Duration::from_secs(config.interval.unwrap_or(DEFAULT_HOST_INTERVAL_SECONDS).max(1));
+    let metrics = config.metrics.clone().unwrap_or_else(default_metrics);
+    let disk = config.disk.clone().unwrap_or("/".to_string());
+    println!("Collecting host metrics every {}s", interval.as_secs());
+
+    let mut sampler = Sampler::default();
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let now = chrono::Utc::now().naive_utc();
+        let points = sampler
+            .sample(&metrics, Path::new(&disk))
+            .into_iter()
+            .map(|(series, value)| (series.to_string(), now, value))
+            .collect::<Vec<_>>();
+
+        let result = env.db.put_all(points).await;
+        if let Err(e) = result {
+            println!("Error while writing host metrics: {e}");
+        }
+    }
+}
+
+/// All metrics, except the temperature if the host has no thermal zone
+fn default_metrics() -> Vec<HostMetric> {
+    let mut metrics = vec![HostMetric::Load, HostMetric::Cpu, HostMetric::Memory, HostMetric::Disk];
+    if Path::new(THERMAL_ZONE).exists() {
+        metrics.push(HostMetric::Temperature);
+    }
+    metrics
+}
+
+#[derive(Default)]
+struct Sampler {
+    /// Busy and total jiffies from the previous sample, CPU usage is the change between samples
+    previous_cpu: Option<(u64, u64)>,
+}
+
+impl Sampler {
+    fn sample(&mut self, metrics: &[HostMetric], disk: &Path) -> Vec<(&'static str, f64)> {
+        let mut points = vec![];
+        for metric in metrics {
+            let result = match metric {
+                HostMetric::Load => read("/proc/loadavg").and_then(|s| parse_loadavg(&s)),
+                HostMetric::Cpu => read("/proc/stat").and_then(|s| self.cpu_used_pct(&s)),
+                HostMetric::Memory => read("/proc/meminfo").and_then(|s| parse_meminfo(&s)),
+                HostMetric::Disk => disk_used_pct(disk),
+                HostMetric::Temperature => read(THERMAL_ZONE).and_then(|s| parse_thermal_zone(&s)),
+            };
+            match result {
+                Ok(values) => points.extend(values),
+                Err(e) => println!("Failed to read host metric {metric:?}: {e}"),
+            }
+        }
+        points
+    }
+
+    fn cpu_used_pct(&mut self, stat: &str) -> Result<Vec<(&'static str, f64)>, String> {
+        let (busy, total) = parse_cpu_stat(stat)?;
+        let points = match self.previous_cpu.replace((busy, total)) {
+            Some((previous_busy, previous_total)) if total > previous_total => {
+                let used = (busy.saturating_sub(previous_busy)) as f64 / (total - previous_total) as f64;
+                vec![("host_cpu_used_pct", used * 100.0)]
+            },
+            _ => vec![],
+        };
+        Ok(points)
+    }
+}
+
+fn read(path: &str) -> Result<String, String> {
+    std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))
+}
+
+fn parse_loadavg(loadavg: &str) -> Result<Vec<(&'static str, f64)>, String> {
+    let loads = loadavg
+        .split_whitespace()
+        .take(3)
+        .map(|l| l.parse::<f64>().map_err(|_| format!("Invalid load average: {l}")))
+        .collect::<Result<Vec<_>, _>>()?;
+    match loads.as_slice() {
+        [load_1m, load_5m, load_15m] => Ok(vec![
+            ("host_cpu_load_1m", *load_1m),
+            ("host_cpu_load_5m", *load_5m),
+            ("host_cpu_load_15m", *load_15m),
+        ]),
+        _ => Err("Invalid /proc/loadavg".to_string()),
+    }
+}
+
+/// Returns the busy and total jiffies of all CPUs from the first line of `/proc/stat`
+fn parse_cpu_stat(stat: &str) -> Result<(u64, u64), String> {
+    let jiffies = stat
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("cpu "))
+        .ok_or("Invalid /proc/stat")?
+        .split_whitespace()
+        .map(|j| j.parse::<u64>().map_err(|_| format!("Invalid /proc/stat value: {j}")))
+        .collect::<Result<Vec<_>, _>>()?;
+    // user nice system idle iowait irq softirq steal, guest time is already included in user
+    let total = jiffies.iter().take(8).sum::<u64>();
+    let idle = jiffies.get(3).copied().unwrap_or_default() + jiffies.get(4).copied().unwrap_or_default();
+    Ok((total - idle, total))
+}
+
+fn parse_meminfo(meminfo: &str) -> Result<Vec<(&'static str, f64)>, String> {
+    let field = |name: &str| -> Option<f64> {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|value| value.split_whitespace().next()?.parse::<f64>().ok())
+    };
+
+    let total = field("MemTotal").ok_or("Missing MemTotal in /proc/meminfo")?;
+    let available = field("MemAvailable").ok_or("Missing MemAvailable in /proc/meminfo")?;
+    let mut points = vec![
+        ("host_mem_used_pct", (1.0 - available / total) * 100.0),
+        ("host_mem_available_mb", available / 1024.0),
+    ];
+
+    if let (Some(swap_total), Some(swap_free)) = (field("SwapTotal"), field("SwapFree")) {
+        if swap_total > 0.0 {
+            points.push(("host_swap_used_pct", (1.0 - swap_free / swap_total) * 100.0));
+        }
+    }
+
+    Ok(points)
+}
+
+fn parse_thermal_zone(temp: &str) -> Result<Vec<(&'static str, f64)>, String> {
+    let millidegrees = temp.trim().parse::<f64>().map_err(|_| format!("Invalid temperature: {temp}"))?;
+    Ok(vec![("host_cpu_temp_c", millidegrees / 1000.0)])
+}
+
+fn disk_used_pct(path: &Path) -> Result<Vec<(&'static str, f64)>, String> {
+    let stat = nix::sys::statvfs::statvfs(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let total = stat.blocks() as f64;
+    let available = stat.blocks_available() as f64;
+    if total == 0.0 {
+        return Err(format!("{} has no blocks", path.display()));
+    }
+    Ok(vec![("host_disk_used_pct", (1.0 - available / total) * 100.0)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc_files() {
+        assert_eq!(parse_loadavg("0.35 0.42 0.40 1/73 12271\n").unwrap(), vec![
+            ("host_cpu_load_1m", 0.35),
+            ("host_cpu_load_5m", 0.42),
+            ("host_cpu_load_15m", 0.40),
+        ]);
+
+        assert_eq!(parse_cpu_stat("cpu  100 0 50 800 50 0 0 0 0 0\ncpu0 100 0 50 800 50 0 0 0 0 0\n").unwrap(), (150, 1000));
+
+        let meminfo = "MemTotal:        1000000 kB\nMemFree:          100000 kB\nMemAvailable:     250000 kB\nSwapTotal:             0 kB\nSwapFree:              0 kB\n";
+        assert_eq!(parse_meminfo(meminfo).unwrap(), vec![
+            ("host_mem_used_pct", 75.0),
+            ("host_mem_available_mb", 250000.0 / 1024.0),
+        ]);
+
+        assert_eq!(parse_thermal_zone("48312\n").unwrap(), vec![("host_cpu_temp_c", 48.312)]);
+    }
+
+    #[test]
+    fn test_cpu_used_pct() {
+        let mut sampler = Sampler::default();
+        assert!(sampler.cpu_used_pct("cpu  100 0 50 800 50 0 0 0 0 0").unwrap().is_empty());
+        assert_eq!(sampler.cpu_used_pct("cpu  200 0 100 850 50 0 0 0 0 0").unwrap(), vec![("host_cpu_used_pct", 75.0)]);
+    }
+}
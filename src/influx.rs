@@ -0,0 +1,261 @@
+use chrono::{DateTime, NaiveDateTime};
+use crate::model::to_series_name;
+
+/// Timestamp precision of an InfluxDB write request, as given by the `precision` query parameter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Precision {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+    Minutes,
+    Hours,
+}
+
+impl Precision {
+    pub fn parse(precision: Option<&str>) -> Result<Self, String> {
+        match precision {
+            None | Some("n") | Some("ns") => Ok(Precision::Nanoseconds),
+            Some("u") | Some("us") => Ok(Precision::Microseconds),
+            Some("ms") => Ok(Precision::Milliseconds),
+            Some("s") => Ok(Precision::Seconds),
+            Some("m") => Ok(Precision::Minutes),
+            Some("h") => Ok(Precision::Hours),
+            Some(other) => Err(format!("Invalid precision: {other}")),
+        }
+    }
+
+    fn to_datetime(self, timestamp: i64) -> Option<NaiveDateTime> {
+        let time = match self {
+            Precision::Nanoseconds => DateTime::from_timestamp_nanos(timestamp),
+            Precision::Microseconds => DateTime::from_timestamp_micros(timestamp)?,
+            Precision::Milliseconds => DateTime::from_timestamp_millis(timestamp)?,
+            Precision::Seconds => DateTime::from_timestamp(timestamp, 0)?,
+            Precision::Minutes => DateTime::from_timestamp(timestamp.checked_mul(60)?, 0)?,
+            Precision::Hours => DateTime::from_timestamp(timestamp.checked_mul(3600)?, 0)?,
+        };
+        Some(time.naive_utc())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum FieldValue {
+    Number(f64),
+    Text(String),
+}
+
+/// A single line of InfluxDB line protocol:
+/// `measurement[,tag=value...] field=value[,field=value...] [timestamp]`
+#[derive(Debug)]
+pub(crate) struct Line {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, FieldValue)>,
+    pub timestamp: Option<i64>,
+}
+
+impl Line {
+    /// Maps a field of this line onto a series name made of the measurement, the tag values ordered
+    /// by tag key and the field key. A field called `value` doesn't contribute to the name.
+    pub fn series_name(&self, field: &str) -> Option<String> {
+        let mut tags = self.tags.iter().collect::<Vec<_>>();
+        tags.sort();
+
+        let mut parts = vec![self.measurement.as_str()];
+        parts.extend(tags.iter().map(|(_, value)| value.as_str()));
+        if field != "value" {
+            parts.push(field);
+        }
+        to_series_name(&parts.join("_"))
+    }
+}
+
+/// Parses a line protocol request body into points. Lines that can't be parsed are skipped and
+/// reported in the returned list of errors.
+pub(crate) fn parse(body: &str, precision: Precision, now: NaiveDateTime) -> (Vec<(String, NaiveDateTime, f64)>, Vec<String>) {
+    let mut points = vec![];
+    let mut errors = vec![];
+
+    for (line_num, text) in body.lines().enumerate() {
+        let text = text.trim();
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+
+        let line = match parse_line(text) {
+            Ok(line) => line,
+            Err(e) => {
+                errors.push(format!("At line {}. {e}", line_num + 1));
+                continue;
+            }
+        };
+
+        let time = match line.timestamp {
+            Some(timestamp) => match precision.to_datetime(timestamp) {
+                Some(time) => time,
+                None => {
+                    errors.push(format!("At line {}. Timestamp is out of range", line_num + 1));
+                    continue;
+                }
+            },
+            None => now,
+        };
+
+        for (field, value) in line.fields.iter() {
+            // Slapdash only stores numbers so string fields are dropped
+            let FieldValue::Number(value) = value else { continue };
+            let value = *value;
+            if !value.is_finite() {
+                errors.push(format!("At line {}. Field {field} is not a finite number", line_num + 1));
+                continue;
+            }
+            match line.series_name(field) {
+                Some(series) => points.push((series, time, value)),
+                None => errors.push(format!("At line {}. Cannot make a series name for field {field}", line_num + 1)),
+            }
+        }
+    }
+
+    (points, errors)
+}
+
+pub(crate) fn parse_line(line: &str) -> Result<Line, String> {
+    // Quotes only delimit string field values, so they may appear as they are in tag values
+    let series_key = split_unescaped(line, ' ', false)[0];
+    let mut sections = vec![series_key];
+    sections.extend(split_unescaped(&line[series_key.len()..], ' ', true).into_iter().filter(|s| !s.is_empty()));
+
+    let (series_key, field_set, timestamp) = match sections.as_slice() {
+        [series_key, field_set] => (*series_key, *field_set, None),
+        [series_key, field_set, timestamp] => (*series_key, *field_set, Some(*timestamp)),
+        _ => return Err("Invalid format. Expected: measurement[,tag=value...] field=value[,field=value...] [timestamp]".to_string()),
+    };
+
+    let mut series_key = split_unescaped(series_key, ',', false).into_iter();
+    let measurement = unescape(series_key.next().unwrap_or_default());
+    if measurement.is_empty() {
+        return Err("Missing measurement".to_string());
+    }
+
+    let tags = series_key
+        .map(|tag| {
+            let (key, value) = split_pair(tag).ok_or(format!("Invalid tag: {tag}"))?;
+            Ok((unescape(key), unescape(value)))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let fields = split_unescaped(field_set, ',', true)
+        .into_iter()
+        .map(|field| {
+            let (key, value) = split_pair(field).ok_or(format!("Invalid field: {field}"))?;
+            Ok((unescape(key), parse_field_value(value)?))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let timestamp = timestamp
+        .map(|t| t.parse::<i64>().map_err(|_| format!("Invalid timestamp: {t}")))
+        .transpose()?;
+
+    Ok(Line{ measurement, tags, fields, timestamp })
+}
+
+fn parse_field_value(value: &str) -> Result<FieldValue, String> {
+    if let Some(text) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        return Ok(FieldValue::Text(text.replace("\\\"", "\"").replace("\\\\", "\\")));
+    }
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE" => return Ok(FieldValue::Number(1.0)),
+        "f" | "F" | "false" | "False" | "FALSE" => return Ok(FieldValue::Number(0.0)),
+        _ => {}
+    }
+    let number = value.strip_suffix(['i', 'u']).unwrap_or(value);
+    number
+        .parse::<f64>()
+        .map(FieldValue::Number)
+        .map_err(|_| format!("Invalid field value: {value}"))
+}
+
+/// Splits `text` on `separator` unless it is escaped with a backslash or, if `quotes` is set,
+/// inside a double quoted string.
+fn split_unescaped(text: &str, separator: char, quotes: bool) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if quotes && c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(&text[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+fn split_pair(text: &str) -> Option<(&str, &str)> {
+    match split_unescaped(text, '=', false).as_slice() {
+        [key, ..] if !key.is_empty() && key.len() < text.len() => Some((key, &text[key.len() + 1..])),
+        _ => None,
+    }
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(',' | '=' | ' ')) => result.push(chars.next().unwrap()),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        let line = parse_line(r#"weather,location=us\ midwest,season=summer temperature=82i,raining=t,note="hot, \"very\"" 1465839830100400200"#).unwrap();
+        assert_eq!(line.measurement, "weather");
+        assert_eq!(line.tags, vec![("location".to_string(), "us midwest".to_string()), ("season".to_string(), "summer".to_string())]);
+        assert_eq!(line.fields, vec![
+            ("temperature".to_string(), FieldValue::Number(82.0)),
+            ("raining".to_string(), FieldValue::Number(1.0)),
+            ("note".to_string(), FieldValue::Text("hot, \"very\"".to_string())),
+        ]);
+        assert_eq!(line.timestamp, Some(1465839830100400200));
+        assert_eq!(line.series_name("temperature").unwrap(), "weather_us_midwest_summer_temperature");
+
+        let line = parse_line(r#"disk,name=6"\ drive free=1,label="a b" 1465839830"#).unwrap();
+        assert_eq!(line.tags, vec![("name".to_string(), "6\" drive".to_string())]);
+        assert_eq!(line.fields[1], ("label".to_string(), FieldValue::Text("a b".to_string())));
+        assert_eq!(line.timestamp, Some(1465839830));
+
+        assert!(parse_line("weather").is_err());
+        assert!(parse_line("weather temperature=hot").is_err());
+        assert!(parse_line("weather,location temperature=82").is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let now = DateTime::from_timestamp(1_718_292_600, 0).unwrap().naive_utc();
+        let body = "# comment\ntemperature,room=Kitchen value=21.5 1718289000\nbroken\ncpu usage_idle=99,host=\"pi\"\n";
+
+        let (points, errors) = parse(body, Precision::Seconds, now);
+
+        assert_eq!(points, vec![
+            ("temperature_kitchen".to_string(), DateTime::from_timestamp(1_718_289_000, 0).unwrap().naive_utc(), 21.5),
+            ("cpu_usage_idle".to_string(), now, 99.0),
+        ]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("At line 3."));
+    }
+}
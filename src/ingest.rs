@@ -0,0 +1,238 @@
+use std::future::Future;
+use std::sync::Arc;
+use anyhow::anyhow;
+use chrono::NaiveDateTime;
+use tokio::sync::{mpsc, oneshot};
+use crate::storage::Storage;
+
+/// How many writes may wait for a commit before writers have to wait for space in the queue
+const QUEUE_CAPACITY: usize = 1024;
+/// The most points committed in one transaction
+const MAX_BATCH_POINTS: usize = 10_000;
+
+type Points = Vec<(String, NaiveDateTime, f64)>;
+
+struct Write {
+    points: Points,
+    committed: oneshot::Sender<Result<(), String>>,
+}
+
+/// Collects writes from the HTTP handlers and collectors and commits them in batches, so that many
+/// small writes share one transaction. A write completes once its batch is committed. When the
+/// queue is full writers wait for space, which slows clients down instead of failing requests.
+#[derive(Clone)]
+pub(crate) struct IngestQueue(mpsc::Sender<Write>);
+
+impl IngestQueue {
+    /// Returns the queue and the task which commits the queued writes to `storage`. Once `stop`
+    /// fires the task stops taking writes, commits those already queued and finishes.
+    pub fn new(storage: Arc<dyn Storage>, stop: oneshot::Receiver<()>) -> (Self, impl Future<Output = anyhow::Result<()>> + Send + 'static) {
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        (Self(tx), run(storage, rx, stop))
+    }
+
+    pub async fn put_all(&self, points: Points) -> anyhow::Result<()> {
+        let (committed, result) = oneshot::channel();
+        self.0
+            .send(Write { points, committed })
+            .await
+            .map_err(|_| anyhow!("The ingest queue has stopped"))?;
+        result
+            .await
+            .map_err(|_| anyhow!("The ingest queue has stopped"))?
+            .map_err(|e| anyhow!(e))
+    }
+}
+
+/// Commits each write as it arrives. Writes which arrive while a commit is running wait in the
+/// queue and are committed together by the next one, so the busier the server the larger the
+/// batches.
+async fn run(storage: Arc<dyn Storage>, mut rx: mpsc::Receiver<Write>, mut stop: oneshot::Receiver<()>) -> anyhow::Result<()> {
+    let mut stopping = false;
+    loop {
+        let write = match stopping {
+            true => rx.recv().await,
+            false => tokio::select! {
+                write = rx.recv() => write,
+                _ = &mut stop => {
+                    stopping = true;
+                    rx.close();
+                    continue;
+                }
+            },
+        };
+        let Some(write) = write else { break };
+        let mut count = write.points.len();
+        let mut writes = vec![write];
+        while count < MAX_BATCH_POINTS {
+            match rx.try_recv() {
+                Ok(write) => {
+                    count += write.points.len();
+                    writes.push(write);
+                }
+                Err(_) => break,
+            }
+        }
+
+        let points = writes
+            .iter_mut()
+            .flat_map(|write| std::mem::take(&mut write.points))
+            .collect();
+        let result = storage.put_all(points).await.map_err(|e| e.to_string());
+        if let Err(e) = &result {
+            println!("Error while committing {count} points: {e}");
+        }
+        for write in writes {
+            let _ = write.committed.send(result.clone());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::sync::Mutex;
+    use async_trait::async_trait;
+    use crate::env::{Db, Environment};
+    use crate::model::{Bucket, Point, SeriesInfo, SeriesMetadata, TimeRange};
+
+    /// Writes to the test database, recording the size of each batch committed
+    struct CountingStorage {
+        db: Db,
+        batches: Mutex<Vec<usize>>,
+    }
+
+    impl CountingStorage {
+        fn new(env: &Environment) -> Arc<Self> {
+            Arc::new(Self { db: env.db.clone(), batches: Mutex::default() })
+        }
+
+        fn batches(&self) -> Vec<usize> {
+            self.batches.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl Storage for CountingStorage {
+        async fn put(&self, series: &str, value: f64, time: Option<NaiveDateTime>) -> anyhow::Result<()> {
+            self.db.put(series, value, time).await
+        }
+        async fn put_all(&self, points: Vec<(String, NaiveDateTime, f64)>) -> anyhow::Result<()> {
+            let len = points.len();
+            self.db.put_all(points).await?;
+            self.batches.lock().unwrap().push(len);
+            Ok(())
+        }
+        async fn get(&self, series: &str, range: TimeRange, buckets: i64) -> anyhow::Result<Vec<Bucket>> {
+            self.db.get(series, range, buckets).await
+        }
+        async fn get_last(&self, series: &str, until: Option<NaiveDateTime>) -> anyhow::Result<Option<Point>> {
+            self.db.get_last(series, until).await
+        }
+        async fn get_points(&self, series: &str, from: Option<NaiveDateTime>, to: Option<NaiveDateTime>) -> anyhow::Result<Vec<Point>> {
+            self.db.get_points(series, from, to).await
+        }
+        async fn list_series(&self) -> anyhow::Result<Vec<(i64, String)>> {
+            self.db.list_series().await
+        }
+        async fn find_series(&self, name: &str, labels: Option<&[(String, String)]>) -> anyhow::Result<Vec<(i64, String)>> {
+            self.db.find_series(name, labels).await
+        }
+        async fn delete_before(&self, series_id: i64, time: NaiveDateTime) -> anyhow::Result<u64> {
+            self.db.delete_before(series_id, time).await
+        }
+        async fn list_series_info(&self, series: Option<&str>) -> anyhow::Result<Vec<SeriesInfo>> {
+            self.db.list_series_info(series).await
+        }
+        async fn rename_series(&self, from: &str, to: &str) -> anyhow::Result<()> {
+            self.db.rename_series(from, to).await
+        }
+        async fn delete_series(&self, series: &str, from: Option<NaiveDateTime>, to: Option<NaiveDateTime>) -> anyhow::Result<u64> {
+            self.db.delete_series(series, from, to).await
+        }
+        async fn merge_series(&self, from: &str, into: &str) -> anyhow::Result<u64> {
+            self.db.merge_series(from, into).await
+        }
+        async fn get_metadata(&self, series: &str) -> anyhow::Result<SeriesMetadata> {
+            self.db.get_metadata(series).await
+        }
+        async fn set_metadata(&self, series: &str, metadata: &SeriesMetadata) -> anyhow::Result<()> {
+            self.db.set_metadata(series, metadata).await
+        }
+        async fn vacuum(&self) -> anyhow::Result<()> {
+            self.db.vacuum().await
+        }
+        async fn backup(&self, path: &Path) -> anyhow::Result<()> {
+            self.db.backup(path).await
+        }
+        async fn roll_up(&self, resolution: i64, until: NaiveDateTime) -> anyhow::Result<()> {
+            self.db.roll_up(resolution, until).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_writes_are_batched() {
+        let dir = std::env::temp_dir().join(format!("slapdash-ingest-batched-{}", std::process::id()));
+        let env = Environment::for_test(&dir).await;
+        let storage = CountingStorage::new(&env);
+        let (stop, stop_rx) = oneshot::channel();
+        let (queue, task) = IngestQueue::new(storage.clone(), stop_rx);
+        let task = tokio::spawn(task);
+
+        let now = chrono::Utc::now().naive_utc();
+        let writes = (0..2000).map(|i| {
+            let queue = queue.clone();
+            let time = now - chrono::Duration::milliseconds(i);
+            tokio::spawn(async move { queue.put_all(vec![(format!("series_{}", i % 3), time, i as f64)]).await })
+        });
+        for write in writes.collect::<Vec<_>>() {
+            write.await.unwrap().unwrap();
+        }
+
+        let batches = storage.batches();
+        assert_eq!(batches.iter().sum::<usize>(), 2000);
+        assert!(batches.len() < 100, "{} commits for 2000 writes", batches.len());
+        let mut stored = 0;
+        for series in ["series_0", "series_1", "series_2"] {
+            stored += env.db.get_points(series, None, None).await.unwrap().len();
+        }
+        assert_eq!(stored, 2000);
+
+        stop.send(()).unwrap();
+        task.await.unwrap().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_stop_commits_queued_writes() {
+        let dir = std::env::temp_dir().join(format!("slapdash-ingest-stop-{}", std::process::id()));
+        let env = Environment::for_test(&dir).await;
+        let storage = CountingStorage::new(&env);
+        let (stop, stop_rx) = oneshot::channel();
+        let (queue, task) = IngestQueue::new(storage.clone(), stop_rx);
+        let task = tokio::spawn(task);
+
+        let now = chrono::Utc::now().naive_utc();
+        let writes: Vec<_> = (0..10)
+            .map(|i| {
+                let queue = queue.clone();
+                let time = now - chrono::Duration::milliseconds(i);
+                tokio::spawn(async move { queue.put_all(vec![("series".to_string(), time, i as f64)]).await })
+            })
+            .collect();
+        // Let the writes reach the queue, then stop it while they wait to be committed
+        tokio::task::yield_now().await;
+        stop.send(()).unwrap();
+        task.await.unwrap().unwrap();
+
+        for write in writes {
+            write.await.unwrap().unwrap();
+        }
+        assert_eq!(storage.batches().iter().sum::<usize>(), 10);
+        assert_eq!(env.db.get_points("series", None, None).await.unwrap().len(), 10);
+        assert!(queue.put_all(vec![("series".to_string(), now, 0.0)]).await.is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::str::Chars;
+use regex::Regex;
+use crate::cli::validate_name;
+use crate::model::glob_to_regex;
+use crate::storage::Storage;
+
+/// How a selector compares the value of a label
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Re,
+    NotRe,
+}
+
+/// A label name, how it is compared and the value it is compared with
+type Label = (String, Op, String);
+
+/// A name followed by optional labels or matchers, e.g. `panel_power_w{mppt=~'a|c'}`. Values may
+/// be quoted with single or double quotes, which they must be to contain `,` or `}`.
+fn parse(text: &str) -> Result<(String, Vec<Label>), String> {
+    let Some(open) = text.find('{') else {
+        return Ok((text.trim().to_string(), vec![]));
+    };
+    let name = text[..open].trim().to_string();
+    let mut chars = text[open + 1..].chars().peekable();
+    let mut labels = vec![];
+
+    loop {
+        skip_whitespace(&mut chars);
+        if chars.peek() == Some(&'}') && labels.is_empty() {
+            chars.next();
+            break;
+        }
+        let key: String = std::iter::from_fn(|| chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_')).collect();
+        if key.is_empty() {
+            return Err("Expected a label name".to_string());
+        }
+        skip_whitespace(&mut chars);
+        let op = match (chars.next(), chars.peek()) {
+            (Some('='), Some('~')) => { chars.next(); Op::Re },
+            (Some('='), _) => Op::Eq,
+            (Some('!'), Some('=')) => { chars.next(); Op::Ne },
+            (Some('!'), Some('~')) => { chars.next(); Op::NotRe },
+            _ => return Err(format!("Expected =, !=, =~ or !~ after {key}")),
+        };
+        skip_whitespace(&mut chars);
+        let value = match chars.peek() {
+            Some(&quote) if quote == '"' || quote == '\'' => {
+                chars.next();
+                let value: String = std::iter::from_fn(|| chars.next_if(|c| *c != quote)).collect();
+                if chars.next() != Some(quote) {
+                    return Err(format!("Missing closing {quote} in the value of {key}"));
+                }
+                value
+            },
+            _ => std::iter::from_fn(|| chars.next_if(|c| *c != ',' && *c != '}')).collect::<String>().trim().to_string(),
+        };
+        labels.push((key, op, value));
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            Some(',') => {},
+            Some('}') => break,
+            _ => return Err("Expected , or } after a label".to_string()),
+        }
+    }
+
+    if chars.any(|c| !c.is_whitespace()) {
+        return Err("Unexpected text after }".to_string());
+    }
+    Ok((name, labels))
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+/// Joins a name and labels into a series name. Labels are ordered by key so that the same labels
+/// always make the same series.
+pub(crate) fn series_name(name: &str, labels: &BTreeMap<String, String>) -> String {
+    if labels.is_empty() {
+        return name.to_string();
+    }
+    let labels: Vec<_> = labels.iter().map(|(key, value)| format!("{key}=\"{value}\"")).collect();
+    format!("{name}{{{}}}", labels.join(","))
+}
+
+/// Splits and validates a series name with optional labels, e.g. `panel_power_w{mppt="a"}`
+pub(crate) fn parse_series(text: &str) -> Result<(String, BTreeMap<String, String>), String> {
+    let (name, parsed) = parse(text)?;
+    let name = validate_name(&name)?;
+    let mut labels = BTreeMap::new();
+    for (key, op, value) in parsed {
+        if op != Op::Eq {
+            return Err("Labels must be given as key=value".to_string());
+        }
+        validate_label(&key, &value)?;
+        if labels.insert(key.clone(), value).is_some() {
+            return Err(format!("Label {key} is given twice"));
+        }
+    }
+    Ok((name, labels))
+}
+
+pub(crate) fn validate_label(key: &str, value: &str) -> Result<(), String> {
+    if !Regex::new("^[a-z_][a-z0-9_]*$").unwrap().is_match(key) {
+        return Err(format!("Label name {key} must contain only lowercase letters, digits and underscores"));
+    }
+    if value.is_empty() || value.contains('"') || value.chars().any(char::is_control) {
+        return Err(format!("Label {key} must have a value without \" or control characters"));
+    }
+    Ok(())
+}
+
+/// Validates a series name with optional labels, returning it with its labels in order
+pub(crate) fn validate_series(text: &str) -> Result<String, String> {
+    let (name, labels) = parse_series(text)?;
+    Ok(series_name(&name, &labels))
+}
+
+/// Puts the labels of an existing series' name in order, so it can be given as typed
+pub(crate) fn canonical(text: &str) -> String {
+    match text.contains('{') {
+        true => validate_series(text).unwrap_or(text.to_string()),
+        false => text.to_string(),
+    }
+}
+
+/// Returns the labels of a series name, or none if it has no valid labels
+pub(crate) fn labels_of(series: &str) -> (&str, BTreeMap<String, String>) {
+    match (series.find('{'), parse(series)) {
+        (Some(open), Ok((_, labels))) => (&series[..open], labels.into_iter().map(|(key, _, value)| (key, value)).collect()),
+        _ => (series, BTreeMap::new()),
+    }
+}
+
+struct Matcher {
+    key: String,
+    op: Op,
+    value: String,
+    regex: Option<Regex>,
+}
+
+impl Matcher {
+    /// A label which a series doesn't have matches as an empty value
+    fn matches(&self, value: Option<&String>) -> bool {
+        let value = value.map(String::as_str).unwrap_or_default();
+        match (self.op, &self.regex) {
+            (Op::Eq, _) => value == self.value,
+            (Op::Ne, _) => value != self.value,
+            (Op::Re, Some(regex)) => regex.is_match(value),
+            (Op::NotRe, Some(regex)) => !regex.is_match(value),
+            _ => false,
+        }
+    }
+}
+
+/// Selects series by name and labels, e.g. `panel_power_w{mppt=~'a|c',site!="shed"}`. The name
+/// may be a glob. Without braces it selects only series without labels, with braces (even `{}`)
+/// the series of that name whatever their labels, unless they are narrowed by matchers. `=~` and
+/// `!~` match the whole value against a regular expression.
+pub(crate) struct Selector {
+    glob: String,
+    name: Regex,
+    /// Whether the selector has braces, so it selects series with labels
+    labelled: bool,
+    matchers: Vec<Matcher>,
+}
+
+impl Selector {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (glob, parsed) = parse(text)?;
+        if glob.is_empty() {
+            return Err("A selector needs a series name".to_string());
+        }
+        let name = glob_to_regex(&glob).map_err(|e| e.to_string())?;
+        let matchers = parsed
+            .into_iter()
+            .map(|(key, op, value)| {
+                let regex = match op {
+                    Op::Re | Op::NotRe => Some(Regex::new(&format!("^(?:{value})$")).map_err(|e| format!("Invalid regular expression for {key}: {e}"))?),
+                    Op::Eq | Op::Ne => None,
+                };
+                Ok(Matcher{ key, op, value, regex })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self{ glob, name, labelled: text.contains('{'), matchers })
+    }
+
+    pub fn matches(&self, series: &str) -> bool {
+        let (name, labels) = labels_of(series);
+        if !self.labelled && !labels.is_empty() {
+            return false;
+        }
+        self.name.is_match(name) && self.matchers.iter().all(|m| m.matches(labels.get(&m.key)))
+    }
+
+    /// The name as a SQL `LIKE` pattern escaped with `\`
+    fn name_like(&self) -> String {
+        self.glob
+            .chars()
+            .map(|c| match c {
+                '*' => "%".to_string(),
+                '?' => "_".to_string(),
+                '%' | '_' | '\\' => format!("\\{c}"),
+                c => c.to_string(),
+            })
+            .collect()
+    }
+
+    /// The labels a series must have, which the database looks up before the other matchers are
+    /// checked. `None` if the selector only selects series without labels.
+    fn required_labels(&self) -> Option<Vec<(String, String)>> {
+        self.labelled.then(|| {
+            self.matchers
+                .iter()
+                .filter(|m| m.op == Op::Eq && !m.value.is_empty())
+                .map(|m| (m.key.clone(), m.value.clone()))
+                .collect()
+        })
+    }
+
+    /// Returns the names of the matching series in order
+    pub async fn resolve(&self, db: &dyn Storage) -> anyhow::Result<Vec<String>> {
+        Ok(db
+            .find_series(&self.name_like(), self.required_labels().as_deref())
+            .await?
+            .into_iter()
+            .map(|(_, name)| name)
+            .filter(|name| self.matches(name))
+            .collect())
+    }
+}
+
+/// Short names which tell the series apart, made of the labels whose values differ between them.
+/// The names are empty for a single series.
+pub(crate) fn legends(series: &[String]) -> Vec<String> {
+    let parsed: Vec<_> = series.iter().map(|s| labels_of(s)).collect();
+    let differs = |key: &String| parsed.iter().any(|(_, labels)| labels.get(key) != parsed[0].1.get(key));
+    let names_differ = parsed.iter().any(|(name, _)| *name != parsed[0].0);
+    parsed
+        .iter()
+        .map(|(name, labels)| {
+            if series.len() < 2 {
+                return String::default();
+            }
+            let mut parts: Vec<_> = labels.iter().filter(|(key, _)| differs(key)).map(|(key, value)| format!("{key}={value}")).collect();
+            if names_differ || parts.is_empty() {
+                parts.insert(0, name.to_string());
+            }
+            parts.join(" ")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::Environment;
+
+    #[test]
+    fn test_labels() {
+        assert_eq!(validate_series("panel_power_w").unwrap(), "panel_power_w");
+        assert_eq!(validate_series("panel_power_w{site=shed, mppt='a'}").unwrap(), "panel_power_w{mppt=\"a\",site=\"shed\"}");
+        assert!(validate_series("panel_power_w{mppt=~'a'}").is_err());
+        assert!(validate_series("panel_power_w{mppt=\"a\"").is_err());
+        assert!(validate_series("panel_power_w{Mppt=a}").is_err());
+        assert!(validate_series("panel_power_w{mppt=a,mppt=b}").is_err());
+
+        let selector = Selector::parse("panel_power_w{mppt=~'a|c'}").unwrap();
+        assert!(selector.matches("panel_power_w{mppt=\"a\",site=\"shed\"}"));
+        assert!(!selector.matches("panel_power_w{mppt=\"b\"}"));
+        assert!(!selector.matches("panel_power_w"));
+        assert!(!selector.matches("panel_power_wh{mppt=\"a\"}"));
+        let selector = Selector::parse("panel_power_w{site!=\"shed\"}").unwrap();
+        assert!(selector.matches("panel_power_w"));
+        assert!(!selector.matches("panel_power_w{mppt=\"a\",site=\"shed\"}"));
+        assert!(!Selector::parse("panel_*").unwrap().matches("panel_power_w{mppt=\"a\"}"));
+        assert!(Selector::parse("panel_*{}").unwrap().matches("panel_power_w{mppt=\"a\"}"));
+
+        let series = ["panel_power_w{mppt=\"a\",site=\"shed\"}".to_string(), "panel_power_w{mppt=\"c\",site=\"shed\"}".to_string()];
+        assert_eq!(legends(&series), vec!["mppt=a", "mppt=c"]);
+        assert_eq!(legends(&series[..1]), vec![""]);
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let dir = std::env::temp_dir().join(format!("slapdash-labels-{}", std::process::id()));
+        let env = Environment::for_test(&dir).await;
+        let time = chrono::Utc::now().naive_utc();
+        let series = ["panel_power_w", "panel_power_w{mppt=\"a\",site=\"shed\"}", "panel_power_w{mppt=\"b\",site=\"shed\"}", "panel_power_w{mppt=\"a\",site=\"roof\"}", "panel_power_wh{mppt=\"a\"}"];
+        env.db.put_all(series.iter().map(|s| (s.to_string(), time, 1.0)).collect()).await.unwrap();
+        env.db.rename_series(series[3], "panel_power_w{mppt=\"c\",site=\"roof\"}").await.unwrap();
+
+        let resolve = async |text: &str| Selector::parse(text).unwrap().resolve(&*env.db).await.unwrap();
+        assert_eq!(resolve("panel_power_w").await, vec![series[0]]);
+        assert_eq!(resolve("panel_power_w{}").await.len(), 4);
+        assert_eq!(resolve("panel_power_w{site=\"shed\",mppt=\"a\"}").await, vec![series[1]]);
+        assert_eq!(resolve("panel_power_w{mppt=\"c\"}").await, vec!["panel_power_w{mppt=\"c\",site=\"roof\"}"]);
+        assert_eq!(resolve("panel_power_w{mppt=\"a\",site!=\"shed\"}").await, Vec::<String>::new());
+        assert_eq!(resolve("panel_*{mppt=\"a\"}").await, vec![series[4], series[1]]);
+        assert_eq!(resolve("panel?power_w").await, vec![series[0]]);
+        assert_eq!(resolve("panel%").await, Vec::<String>::new());
+
+        env.db.delete_series(series[1], None, None).await.unwrap();
+        assert_eq!(resolve("panel_power_w{site=\"shed\"}").await, vec![series[2]]);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
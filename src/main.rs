@@ -5,34 +5,76 @@ mod db;
 mod env;
 mod cli;
 mod server;
+mod influx;
+mod statsd;
+mod graphite;
+mod scrape;
+mod host;
+mod exec;
+mod mqtt;
+mod webhook;
+mod retention;
+mod rollup;
+mod storage;
+mod postgres;
+mod export;
+mod backup;
+mod ingest;
+mod labels;
+mod expr;
 
 use std::path::PathBuf;
 use anyhow::anyhow;
 use chrono::NaiveDateTime;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use cli::Cli;
 use crate::cli::Commands;
 use crate::cli::DashboardCommands;
+use crate::cli::{MetadataField, SeriesCommands};
 use crate::env::Dashboards;
 use env::Environment;
 use server::Server;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Write};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
-    let env = Environment::load().await?;
+    let dir = Environment::path(cli.data_dir.as_deref())?;
+    // Restoring replaces the database, so it must not be opened first
+    if let Commands::Restore { filename } = &cli.command {
+        return backup::restore(&dir, filename).await;
+    }
+    let env = Environment::load(&dir).await?;
 
     match cli.command {
-        Commands::Serve{listen_addr, secret} => Server::serve(&listen_addr, &secret).await?,
+        Commands::Serve{listen_addr, secret} => Server::serve(&dir, &listen_addr, &secret).await?,
         Commands::Dashboard { command } => match command {
             DashboardCommands::New { name } => {
-                let msg = Dashboards::new_dashboard(&name)?;
+                let msg = Dashboards::new_dashboard(&dir, &name)?;
                 println!("{msg}");
             }
         },
-        Commands::Push { series, value } => push(&env, &series, value).await?,
+        Commands::Series { command } => series(&env, command).await?,
+        Commands::Push { series, value, time, labels } => {
+            let (name, mut all_labels) = labels::parse_series(&series).map_err(|e| anyhow!(e))?;
+            all_labels.extend(labels);
+            push(&env, &labels::series_name(&name, &all_labels), value, time).await?
+        },
+        Commands::Export { series, from, to, format, output } => {
+            let series = export::resolve(&*env.db, &series).await?;
+            let out: Box<dyn Write + Send> = match output {
+                Some(filename) => Box::new(std::io::BufWriter::new(File::create(filename)?)),
+                None => Box::new(std::io::BufWriter::new(std::io::stdout())),
+            };
+            let mut exporter = export::Exporter::new(format, out)?;
+            for name in series {
+                exporter.write_series(&name, &env.db.get_points(&name, from, to).await?)?;
+            }
+            exporter.finish()?;
+        },
+        Commands::Backup { filename } => backup::backup(&env, &filename).await?,
+        Commands::Restore { .. } => unreachable!("Restore is handled before the environment is loaded"),
         Commands::List => {
             let dashboards = env.dashboards.list();
             println!("Dashboards:\n\t{}", dashboards.join("\n\t"));
@@ -45,6 +87,94 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn series(env: &Environment, command: SeriesCommands) -> anyhow::Result<()> {
+    match command {
+        SeriesCommands::List => {
+            let series = env.db.list_series_info(None).await?;
+            let width = series.iter().map(|s| s.name.len()).max().unwrap_or(0).max(6);
+            println!("{:width$}  {:>10}  {:19}  LAST", "SERIES", "POINTS", "FIRST");
+            for s in series {
+                let first = s.first.map(|t| t.to_string()).unwrap_or_default();
+                let last = s.last.map(|t| t.to_string()).unwrap_or_default();
+                println!("{}", format!("{:width$}  {:>10}  {first:19}  {last}", s.name, s.points).trim_end());
+            }
+        },
+        SeriesCommands::Inspect { name } => {
+            let name = labels::canonical(&name);
+            let s = env.db.list_series_info(Some(&name)).await?
+                .pop()
+                .ok_or(anyhow!("Series {name} not found"))?;
+            let metadata = env.db.get_metadata(&name).await?;
+            let none = || "-".to_string();
+            let field = |label: &str, value: String| println!("{:13}{value}", format!("{label}:"));
+            field("Series", s.name);
+            field("Points", s.points.to_string());
+            field("First", s.first.map(|t| t.to_string()).unwrap_or_else(none));
+            field("Last", s.last.map(|t| t.to_string()).unwrap_or_else(none));
+            if let Some(last) = env.db.get_last(&name, None).await? {
+                field("Value", metadata.format(last.value, 2));
+            }
+            if let Some(unit) = metadata.unit {
+                field("Unit", unit);
+            }
+            if let Some(description) = metadata.description {
+                field("Description", description);
+            }
+            if let Some(decimals) = metadata.decimals {
+                field("Decimals", decimals.to_string());
+            }
+            if metadata.min.is_some() || metadata.max.is_some() {
+                let bound = |b: Option<f64>| b.map(|b| b.to_string()).unwrap_or_default();
+                field("Range", format!("{}..{}", bound(metadata.min), bound(metadata.max)));
+            }
+        },
+        SeriesCommands::Rename { from, to } => {
+            let from = labels::canonical(&from);
+            env.db.rename_series(&from, &to).await?;
+            println!("Renamed {from} to {to}");
+        },
+        SeriesCommands::Delete { name, from, to } => {
+            let name = labels::canonical(&name);
+            let deleted = env.db.delete_series(&name, from, to).await?;
+            if from.is_none() && to.is_none() {
+                println!("Deleted {name} and its {deleted} points");
+            } else {
+                println!("Deleted {deleted} points from {name}");
+            }
+        },
+        SeriesCommands::Merge { from, into } => {
+            let from = labels::canonical(&from);
+            let moved = env.db.merge_series(&from, &into).await?;
+            println!("Moved {moved} points from {from} into {into}");
+        },
+        SeriesCommands::Set { name, unit, description, decimals, min, max, clear } => {
+            let mut metadata = env.db.get_metadata(&name).await?;
+            let clear = match clear {
+                Some(fields) if fields.is_empty() => MetadataField::value_variants().to_vec(),
+                fields => fields.unwrap_or_default(),
+            };
+            for field in clear {
+                match field {
+                    MetadataField::Unit => metadata.unit = None,
+                    MetadataField::Description => metadata.description = None,
+                    MetadataField::Decimals => metadata.decimals = None,
+                    MetadataField::Min => metadata.min = None,
+                    MetadataField::Max => metadata.max = None,
+                }
+            }
+            metadata.unit = unit.or(metadata.unit).filter(|unit| !unit.is_empty());
+            metadata.description = description.or(metadata.description).filter(|description| !description.is_empty());
+            metadata.decimals = decimals.or(metadata.decimals);
+            metadata.min = min.or(metadata.min);
+            metadata.max = max.or(metadata.max);
+            metadata.validate().map_err(|e| anyhow!(e))?;
+            env.db.set_metadata(&name, &metadata).await?;
+            println!("Set the metadata of {name}");
+        },
+    }
+    Ok(())
+}
+
 async fn push_all(env: &Environment, filename: PathBuf) -> anyhow::Result<()> {
     let file = File::open(filename)?;
     let reader = BufReader::new(file);
@@ -52,17 +182,22 @@ async fn push_all(env: &Environment, filename: PathBuf) -> anyhow::Result<()> {
     let points: anyhow::Result<Vec<_>> = reader
         .lines()
         .enumerate()
-        .map(|(line_num, line)| -> anyhow::Result<(String, NaiveDateTime, f32)>{
+        .map(|(line_num, line)| -> anyhow::Result<(String, NaiveDateTime, f64)>{
             let line = line?;
             
-            let cols: Vec<_> = line.split(',').collect();
+            // Series with several labels contain commas, so the columns are split from the end
+            let mut cols: Vec<_> = line.rsplitn(3, ',').collect();
+            cols.reverse();
             if cols.len() != 3 {
                 return Err(anyhow!("At line {}. Invalid format. Each row of the CSV file should contain 3 columns: series, time and point.", &line_num))
             }
 
-            let series = cols[0].to_string();
-            let time = NaiveDateTime::parse_from_str(cols[1], "%Y-%m-%d %H:%M:%S").map_err(|_| anyhow!("At line {}. Invalid format. The time column must be formatted as: 2024-06-13 15:30:00", &line_num))?;
-            let value: f32 = cols[2].parse().map_err(|_| anyhow!("At line {}. Invalid format. The value column must parse as an f32", &line_num))?;
+            let series = match cols[0].strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+                Some(quoted) => labels::canonical(&quoted.replace("\"\"", "\"")),
+                None => labels::canonical(cols[0]),
+            };
+            let time = NaiveDateTime::parse_from_str(cols[1], "%Y-%m-%d %H:%M:%S%.f").map_err(|_| anyhow!("At line {}. Invalid format. The time column must be formatted as: 2024-06-13 15:30:00 or 2024-06-13 15:30:00.250", &line_num))?;
+            let value: f64 = cols[2].parse().map_err(|_| anyhow!("At line {}. Invalid format. The value column must parse as an f64", &line_num))?;
 
             Ok((series, time, value))
         })
@@ -70,16 +205,18 @@ async fn push_all(env: &Environment, filename: PathBuf) -> anyhow::Result<()> {
 
     let points = points?;
 
-    let mut db = env.db.acquire().await?;
-
-    db::put_all(&mut db, points).await
+    env.db.put_all(points).await
 }
 
-async fn push(env: &Environment, series: &str, value: f32) -> anyhow::Result<()> {
+async fn push(env: &Environment, series: &str, value: f64, time: Option<String>) -> anyhow::Result<()> {
     let listen_addr = env.settings.listen_addr;
     let secret = &env.settings.secret;
     let url = format!("http://{listen_addr}/{secret}/{series}/{value}");
-    let response = reqwest::get(&url).await?;
+    let mut request = reqwest::Client::new().get(&url);
+    if let Some(time) = time {
+        request = request.query(&[("time", time)]);
+    }
+    let response = request.send().await?;
     match response.status() {
         reqwest::StatusCode::OK => println!("Pushed {value} to {series}"),
         reqwest::StatusCode::BAD_REQUEST => println!("Failed to push data to {series}: {}", response.text().await?),
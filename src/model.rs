@@ -1,9 +1,10 @@
 use serde::{Deserialize, Serialize};
 use chrono::NaiveDateTime;
-use sqlx::SqliteConnection;
 
-use crate::{db, env::Dashboards, view::{FreshnessWidgetTemplate, GaugeWidgetTemplate, LabelWidgetTemplate, LineWidgetTemplate, RangeWidgetTemplate, ValueWidgetTemplate, WidgetTemplate, WidgetTemplateInner}};
-use std::path::PathBuf;
+use crate::expr::Expr;
+use crate::labels::{self, Selector};
+use crate::storage::Storage;
+use crate::{db, view::{FreshnessWidgetTemplate, GaugeWidgetTemplate, LabelWidgetTemplate, LineSeries, LineWidgetTemplate, RangeWidgetTemplate, ValueWidgetTemplate, WidgetTemplate, WidgetTemplateInner}};
 
 #[derive(Debug,Serialize, Deserialize, Clone, Default)]
 pub(crate) enum Color{
@@ -106,18 +107,241 @@ impl Color{
     }
 }
 
+/// Compiles a glob on series names, where `*` matches any characters and `?` a single character
+pub(crate) fn glob_to_regex(glob: &str) -> Result<regex::Regex, regex::Error> {
+    let pattern = glob
+        .chars()
+        .map(|c| match c {
+            '*' => ".*".to_string(),
+            '?' => ".".to_string(),
+            c => regex::escape(&c.to_string()),
+        })
+        .collect::<String>();
+    regex::Regex::new(&format!("^{pattern}$"))
+}
+
+/// Converts an arbitrary name from an external source into a valid series name containing only
+/// lowercase letters, digits, underscores and hyphens. Returns `None` if nothing usable is left.
+pub(crate) fn to_series_name(name: &str) -> Option<String> {
+    let mut series = String::with_capacity(name.len());
+    for c in name.trim().chars().flat_map(char::to_lowercase) {
+        match c {
+            'a'..='z' | '0'..='9' | '-' => series.push(c),
+            _ if !series.ends_with('_') => series.push('_'),
+            _ => {}
+        }
+    }
+    let series = series.trim_matches('_');
+    if series.is_empty() {
+        None
+    } else {
+        Some(series.to_string())
+    }
+}
+
+/// Reads a numeric value from JSON. Numeric strings are parsed and booleans are 1 or 0.
+pub(crate) fn json_to_number(value: &serde_json::Value) -> Option<f64> {
+    let number = match value {
+        serde_json::Value::Number(n) => n.as_f64(),
+        serde_json::Value::String(s) => s.trim().parse::<f64>().ok(),
+        serde_json::Value::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+        _ => None,
+    };
+    number.filter(|n| n.is_finite())
+}
+
 #[derive(sqlx::FromRow, Serialize, Debug)]
 pub(crate) struct Point{
     pub time: NaiveDateTime,
-    pub value: f32
+    pub value: f64
 }
 
 impl Point{
+    /// The time as unix milliseconds
     pub fn x(&self) -> i64 {
-        self.time.and_utc().timestamp()
+        self.time.and_utc().timestamp_millis()
     }
 }
 
+/// A series and the number and time span of its points
+#[derive(sqlx::FromRow, Debug)]
+pub(crate) struct SeriesInfo{
+    pub name: String,
+    pub points: i64,
+    pub first: Option<NaiveDateTime>,
+    pub last: Option<NaiveDateTime>,
+}
+
+/// How the values of a series are displayed. Widgets fall back to their own defaults for anything
+/// not set.
+#[derive(sqlx::FromRow, Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub(crate) struct SeriesMetadata{
+    pub unit: Option<String>,
+    pub description: Option<String>,
+    /// Number of decimal places
+    pub decimals: Option<i64>,
+    /// Lower end of the range the values are expected to stay in
+    pub min: Option<f64>,
+    /// Upper end of the range the values are expected to stay in
+    pub max: Option<f64>,
+}
+
+impl SeriesMetadata{
+    pub const MAX_DECIMALS: i64 = 10;
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(decimals) = self.decimals {
+            if !(0..=Self::MAX_DECIMALS).contains(&decimals) {
+                return Err(format!("Decimals must be between 0 and {}", Self::MAX_DECIMALS));
+            }
+        }
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min >= max {
+                return Err("Min must be less than max".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Formats a value with the series' decimals, or `default_decimals` if not set, followed by its unit
+    pub fn format(&self, value: f64, default_decimals: usize) -> String {
+        let decimals = self.decimals.map(|d| d as usize).unwrap_or(default_decimals);
+        match self.unit.as_deref() {
+            Some("%") => format!("{value:.decimals$}%"),
+            Some(unit) if !unit.is_empty() => format!("{value:.decimals$} {unit}"),
+            _ => format!("{value:.decimals$}"),
+        }
+    }
+}
+
+/// The span of time a dashboard shows, `seconds` long and ending at `end`, or now if `end` isn't given
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct TimeRange{
+    pub end: Option<NaiveDateTime>,
+    pub seconds: u32,
+}
+
+impl TimeRange{
+    /// The range of the last `seconds` up to now
+    pub fn last(seconds: u32) -> Self {
+        Self{ end: None, seconds }
+    }
+
+    pub fn end(&self) -> NaiveDateTime {
+        self.end.unwrap_or_else(|| chrono::Utc::now().naive_utc())
+    }
+
+    pub fn start(&self) -> NaiveDateTime {
+        self.end() - chrono::TimeDelta::seconds(self.seconds as i64)
+    }
+
+    /// The start and end as unix milliseconds. A range ending now has no end, so that points
+    /// pushed with a clock running slightly ahead are shown.
+    pub fn millis(&self) -> (i64, i64) {
+        let end = self.end.map(|end| end.and_utc().timestamp_millis()).unwrap_or(i64::MAX);
+        (self.start().and_utc().timestamp_millis(), end)
+    }
+}
+
+/// Summary of the points in a time bucket. `time` is the time of the first point.
+#[derive(sqlx::FromRow, Debug)]
+pub(crate) struct Bucket{
+    pub time: NaiveDateTime,
+    pub first: f64,
+    pub last: f64,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+}
+
+/// How a line widget summarizes the points in each bucket
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Aggregation{
+    #[default]
+    First,
+    Last,
+    Avg,
+    Min,
+    Max,
+    /// A band between the minimum and maximum of each bucket
+    Envelope,
+    /// Largest-Triangle-Three-Buckets, which picks the points that best preserve the shape of the line
+    Lttb,
+}
+
+/// LTTB picks its points from this many times more buckets than are displayed
+const LTTB_OVERSAMPLING: i64 = 8;
+
+impl Aggregation{
+    /// The number of buckets to fetch to display `points` points
+    pub fn buckets(&self, points: i64) -> i64 {
+        match self {
+            Aggregation::Lttb => points * LTTB_OVERSAMPLING,
+            _ => points,
+        }
+    }
+
+    /// Returns the line to display and, for an envelope, the lower edge of the band
+    pub fn apply(&self, buckets: &[Bucket], points: usize) -> (Vec<Point>, Vec<Point>) {
+        let line = |value: fn(&Bucket) -> f64| buckets
+            .iter()
+            .map(|b| Point{ time: b.time, value: value(b) })
+            .collect::<Vec<_>>();
+
+        match self {
+            Aggregation::First => (line(|b| b.first), vec![]),
+            Aggregation::Last => (line(|b| b.last), vec![]),
+            Aggregation::Avg => (line(|b| b.avg), vec![]),
+            Aggregation::Min => (line(|b| b.min), vec![]),
+            Aggregation::Max => (line(|b| b.max), vec![]),
+            Aggregation::Envelope => (line(|b| b.max), line(|b| b.min)),
+            // The extreme of each bucket furthest from its average is picked from, so that a spike
+            // isn't averaged away before LTTB sees it
+            Aggregation::Lttb => (lttb(line(|b| if b.max - b.avg >= b.avg - b.min { b.max } else { b.min }), points), vec![]),
+        }
+    }
+}
+
+/// Downsamples to `threshold` points using Largest-Triangle-Three-Buckets. The first and last
+/// points are kept and from each bucket in between the point which forms the largest triangle
+/// with the previously picked point and the average of the next bucket is picked.
+pub(crate) fn lttb(points: Vec<Point>, threshold: usize) -> Vec<Point> {
+    if threshold < 3 || points.len() <= threshold {
+        return points;
+    }
+
+    let x = |p: &Point| p.x() as f64;
+    let y = |p: &Point| p.value;
+    let bucket_size = (points.len() - 2) as f64 / (threshold - 2) as f64;
+    let bucket = |i: usize| {
+        let start = (i as f64 * bucket_size) as usize + 1;
+        let end = (((i + 1) as f64 * bucket_size) as usize + 1).min(points.len() - 1);
+        start..end
+    };
+
+    let mut picked = vec![0];
+    for i in 0..threshold - 2 {
+        let next = if i + 1 < threshold - 2 { bucket(i + 1) } else { points.len() - 1..points.len() };
+        let next_len = next.len() as f64;
+        let next_x = points[next.clone()].iter().map(x).sum::<f64>() / next_len;
+        let next_y = points[next].iter().map(y).sum::<f64>() / next_len;
+
+        let previous = &points[*picked.last().unwrap()];
+        let best = bucket(i).max_by(|a, b| {
+            let area = |p: &Point| ((x(previous) - next_x) * (y(p) - y(previous)) - (x(previous) - x(p)) * (next_y - y(previous))).abs();
+            area(&points[*a]).total_cmp(&area(&points[*b]))
+        });
+        if let Some(best) = best {
+            picked.push(best);
+        }
+    }
+    picked.push(points.len() - 1);
+
+    let mut points = points.into_iter().map(Some).collect::<Vec<_>>();
+    picked.into_iter().filter_map(|i| points[i].take()).collect()
+}
+
 #[derive(Deserialize, Clone)]
 pub(crate) struct Dashboard{
     pub name: String,
@@ -125,10 +349,6 @@ pub(crate) struct Dashboard{
 }
 
 impl Dashboard {
-    pub fn path(&self) -> anyhow::Result<PathBuf> {
-        Ok(Dashboards::path()?.join(format!("{}.xml", self.name)))
-    }
-
     pub fn width(&self) -> u16 {
         self
             .widgets
@@ -167,29 +387,66 @@ impl Widget{
         self.color.as_ref().map(Color::to_css_stroke).unwrap_or(Color::default().to_css_stroke())
     }
 
-    pub(crate) async fn to_template(&self, db: &mut SqliteConnection, range_seconds: u32) -> anyhow::Result<WidgetTemplate> {
+    pub(crate) async fn to_template(&self, db: &dyn Storage, range: TimeRange) -> anyhow::Result<WidgetTemplate> {
         let inner_template = match &self.typ {
             WidgetType::Value{ series, label } => {
-                let point = db::get_last(db, series).await?.map(|p| p.value);
-                WidgetTemplateInner::Value(ValueWidgetTemplate{ label: label.clone(), point, color: self.stroke_css_color() })
+                let expr = parse_expr(series)?;
+                let latest = latest(db, &expr, range).await?;
+                let names: Vec<_> = latest.iter().map(|(name, _)| name.clone()).collect();
+                let points = labels::legends(&names).into_iter().zip(latest).map(|(legend, (_, value))| (legend, value)).collect();
+                let metadata = metadata(db, &expr, &names).await?;
+                WidgetTemplateInner::Value(ValueWidgetTemplate{ label: label.clone(), points, metadata, color: self.stroke_css_color() })
             },
-            WidgetType::Line{ series, label } => {
-                let data = db::get(db, series, range_seconds).await?;
-                WidgetTemplateInner::Line(LineWidgetTemplate{ label: label.clone(), data, color: self.stroke_css_color(), width: self.width, height: self.height })
+            WidgetType::Line{ series, label, agg } => {
+                let expr = parse_expr(series)?;
+                let series = expr.get(db, range, agg.buckets(db::MAX_POINTS)).await?;
+                let names: Vec<_> = series.iter().map(|(name, _)| name.clone()).collect();
+                let mut lines = vec![];
+                for ((_, buckets), legend) in series.iter().zip(labels::legends(&names)) {
+                    let (data, lower) = agg.apply(buckets, db::MAX_POINTS as usize);
+                    lines.push(LineSeries{ legend, data, lower });
+                }
+                let metadata = metadata(db, &expr, &names).await?;
+                WidgetTemplateInner::Line(LineWidgetTemplate{ label: label.clone(), lines, metadata, color: self.stroke_css_color(), width: self.width, height: self.height })
             },
             WidgetType::Gauge{ series, min, max, label } => {
-                let point = db::get_last(db, series).await?.map(|p| p.value);
-                WidgetTemplateInner::Gauge(GaugeWidgetTemplate{ label: label.clone(), point: point, min: *min, max: *max, color: self.stroke_css_color() })
+                let expr = parse_expr(series)?;
+                let latest = latest(db, &expr, range).await?;
+                // A gauge shows a single value, so a selector matching several series shows none
+                let point = match latest.as_slice() {
+                    [(_, value)] => *value,
+                    _ => None,
+                };
+                let names: Vec<_> = latest.iter().map(|(name, _)| name.clone()).collect();
+                let metadata = metadata(db, &expr, &names).await?;
+                // The dashboard's range takes precedence over the series' expected range. The ends are
+                // taken together, so that a range isn't made of one end from each, and a range
+                // which is still empty falls back to 0 to 100.
+                let (min, max) = match (min, max) {
+                    (None, None) => (metadata.min, metadata.max),
+                    _ => (*min, *max),
+                };
+                let (min, max) = match (min.unwrap_or(0.0), max.unwrap_or(100.0)) {
+                    (min, max) if min < max => (min, max),
+                    _ => (0.0, 100.0),
+                };
+                WidgetTemplateInner::Gauge(GaugeWidgetTemplate{ label: label.clone(), point, matched: names.len(), min, max, metadata, color: self.stroke_css_color() })
             },  
             WidgetType::Label{ text } => {
                 WidgetTemplateInner::Label(LabelWidgetTemplate{ text: text.clone() })
             },
             WidgetType::Freshness{ series } => {
-                let data = db::get_last(db, series).await?;
-                WidgetTemplateInner::Freshness(FreshnessWidgetTemplate{ last_update_time: data.map(|p| p.time) })
+                // Of several series the one updated longest ago is shown
+                let mut times = vec![];
+                for selector in parse_expr(series)?.selectors() {
+                    for name in resolve(db, selector).await? {
+                        times.extend(db.get_last(&name, range.end).await?.map(|p| p.time));
+                    }
+                }
+                WidgetTemplateInner::Freshness(FreshnessWidgetTemplate{ last_update_time: times.into_iter().min(), now: range.end() })
             }
-            WidgetType::Range{ range, label } => {
-                WidgetTemplateInner::Range(RangeWidgetTemplate{ range: *range, label: label.clone() })
+            WidgetType::Range{ range: seconds, label, step } => {
+                WidgetTemplateInner::Range(RangeWidgetTemplate{ range: *seconds, label: label.clone(), step: *step, current: range })
             }
         };
         let template = WidgetTemplate{ config: self.clone(), template: inner_template };
@@ -197,12 +454,138 @@ impl Widget{
     }
 }
 
+/// Returns the names of the series matched by a widget's `series` selector
+async fn resolve(db: &dyn Storage, selector: &str) -> anyhow::Result<Vec<String>> {
+    Selector::parse(selector)
+        .map_err(|e| anyhow::anyhow!("Invalid series selector {selector}: {e}"))?
+        .resolve(db)
+        .await
+}
+
+/// Parses a widget's `series`, which is a series selector or an expression deriving series from others
+fn parse_expr(text: &str) -> anyhow::Result<Expr> {
+    Expr::parse(text).map_err(|e| anyhow::anyhow!("Invalid series expression {text}: {e}"))
+}
+
+/// Returns the name and latest value of each series an expression gives. Stored series give their
+/// last point up to the end of the range, derived series their last bucket in the range.
+async fn latest(db: &dyn Storage, expr: &Expr, range: TimeRange) -> anyhow::Result<Vec<(String, Option<f64>)>> {
+    let Some(selector) = expr.selector() else {
+        let series = expr.get(db, range, db::MAX_POINTS).await?;
+        return Ok(series.into_iter().map(|(name, buckets)| (name, buckets.last().map(|b| b.last))).collect());
+    };
+    let mut latest = vec![];
+    for name in resolve(db, selector).await? {
+        let value = db.get_last(&name, range.end).await?.map(|p| p.value);
+        latest.push((name, value));
+    }
+    Ok(latest)
+}
+
+/// Widgets showing several series display them all with the metadata of the first. Derived series
+/// may be in other units than the series they are made of, so they have none.
+async fn metadata(db: &dyn Storage, expr: &Expr, names: &[String]) -> anyhow::Result<SeriesMetadata> {
+    match (expr.selector(), names.first()) {
+        (Some(_), Some(name)) => db.get_metadata(name).await,
+        _ => Ok(SeriesMetadata::default()),
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub(crate) enum WidgetType{
     Value{ series: String, label: String },
-    Line{ series: String, label: String },
-    Gauge{ series: String, min: f32, max: f32, label: String },
+    Line{ series: String, label: String, agg: Aggregation },
+    Gauge{ series: String, min: Option<f64>, max: Option<f64>, label: String },
     Label{ text: String },
     Freshness{ series: String },
-    Range{ range: u32, label: String },
-}
\ No newline at end of file
+    Range{ range: u32, label: String, step: bool },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregation() {
+        let time = |t: i64| chrono::DateTime::from_timestamp(t, 0).unwrap().naive_utc();
+        let buckets = vec![
+            Bucket{ time: time(0), first: 1.0, last: 2.0, min: 0.0, max: 9.0, avg: 3.0 },
+            Bucket{ time: time(60), first: 2.0, last: 3.0, min: 1.0, max: 4.0, avg: 2.5 },
+        ];
+        let values = |points: Vec<Point>| points.iter().map(|p| p.value).collect::<Vec<_>>();
+
+        let (line, lower) = Aggregation::Max.apply(&buckets, 512);
+        assert_eq!((values(line), lower.len()), (vec![9.0, 4.0], 0));
+        let (upper, lower) = Aggregation::Envelope.apply(&buckets, 512);
+        assert_eq!((values(upper), values(lower)), (vec![9.0, 4.0], vec![0.0, 1.0]));
+        assert_eq!(Aggregation::Lttb.buckets(512), 4096);
+
+        // A spike survives LTTB
+        let points = (0..100).map(|t| Point{ time: time(t), value: if t == 41 { 100.0 } else { (t % 2) as f64 } }).collect();
+        let picked = lttb(points, 10);
+        assert_eq!(picked.len(), 10);
+        assert_eq!((picked[0].x(), picked[9].x()), (0, 99_000));
+        assert!(picked.iter().any(|p| p.value == 100.0));
+        assert!(picked.windows(2).all(|w| w[0].x() < w[1].x()));
+    }
+
+    #[tokio::test]
+    async fn test_lttb_line_keeps_spike() {
+        let dir = std::env::temp_dir().join(format!("slapdash-lttb-{}", std::process::id()));
+        let env = crate::env::Environment::for_test(&dir).await;
+        let end = chrono::DateTime::from_timestamp(1_718_292_600, 0).unwrap().naive_utc();
+        // A point every 5 seconds for a day, so each bucket averages several points
+        let points = (0..17_280)
+            .map(|i| ("power".to_string(), end - chrono::Duration::seconds(5 * i), if i == 5_000 { 1000.0 } else { (i % 2) as f64 }))
+            .collect();
+        env.db.put_all(points).await.unwrap();
+
+        let widget = Widget{ left: 0, top: 0, width: 1, height: 1, color: None, typ: WidgetType::Line{ series: "power".to_string(), label: "Power".to_string(), agg: Aggregation::Lttb } };
+        let template = widget.to_template(&*env.db, TimeRange{ end: Some(end), seconds: 86400 }).await.unwrap();
+        let WidgetTemplateInner::Line(line) = template.template else { panic!("Expected a line widget") };
+        let data = &line.lines[0].data;
+        assert!(data.len() <= db::MAX_POINTS as usize);
+        assert_eq!(data.iter().map(|p| p.value).fold(f64::MIN, f64::max), 1000.0);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_gauge_range() {
+        let dir = std::env::temp_dir().join(format!("slapdash-gauge-{}", std::process::id()));
+        let env = crate::env::Environment::for_test(&dir).await;
+        env.db.put("voltage", 230.0, None).await.unwrap();
+        env.db.set_metadata("voltage", &SeriesMetadata{ min: Some(220.0), ..Default::default() }).await.unwrap();
+
+        let gauge = async |min: Option<f64>, max: Option<f64>| {
+            let widget = Widget{ left: 0, top: 0, width: 1, height: 1, color: None, typ: WidgetType::Gauge{ series: "voltage".to_string(), min, max, label: "Voltage".to_string() } };
+            let template = widget.to_template(&*env.db, TimeRange::last(3600)).await.unwrap();
+            let WidgetTemplateInner::Gauge(gauge) = template.template else { panic!("Expected a gauge widget") };
+            assert!(!gauge.arc_svg().is_empty());
+            (gauge.min, gauge.max)
+        };
+        // A min above the default max can't make a range, so the default is shown
+        assert_eq!(gauge(None, None).await, (0.0, 100.0));
+        assert_eq!(gauge(Some(200.0), Some(250.0)).await, (200.0, 250.0));
+        assert_eq!(gauge(None, Some(250.0)).await, (0.0, 250.0));
+        env.db.set_metadata("voltage", &SeriesMetadata{ min: Some(220.0), max: Some(240.0), ..Default::default() }).await.unwrap();
+        assert_eq!(gauge(None, None).await, (220.0, 240.0));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_series_metadata() {
+        let metadata = SeriesMetadata::default();
+        assert_eq!(metadata.format(2.345, 1), "2.3");
+
+        let metadata = SeriesMetadata{ unit: Some("kWh".to_string()), decimals: Some(0), ..Default::default() };
+        assert_eq!(metadata.format(12.6, 2), "13 kWh");
+        let metadata = SeriesMetadata{ unit: Some("%".to_string()), ..Default::default() };
+        assert_eq!(metadata.format(42.0, 1), "42.0%");
+
+        assert!(SeriesMetadata{ min: Some(0.0), max: Some(100.0), ..Default::default() }.validate().is_ok());
+        assert!(SeriesMetadata{ min: Some(1.0), max: Some(1.0), ..Default::default() }.validate().is_err());
+        assert!(SeriesMetadata{ decimals: Some(-1), ..Default::default() }.validate().is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use std::time::Duration;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS, SubscribeFilter};
+use crate::env::{Environment, MqttCollector, MqttTopic};
+use crate::model::{json_to_number, to_series_name};
+
+const DEFAULT_CLIENT_ID: &str = "slapdash";
+const RECONNECT_AFTER: Duration = Duration::from_secs(5);
+
+/// Subscribes to topics on an MQTT broker and writes each message it receives as a point
+pub(crate) async fn run(config: MqttCollector, env: Environment) -> anyhow::Result<()> {
+    let mut url = config.broker.clone();
+    if !url.contains("client_id=") {
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let client_id = config.client_id.as_deref().unwrap_or(DEFAULT_CLIENT_ID);
+        url = format!("{url}{separator}client_id={client_id}");
+    }
+    let options = MqttOptions::parse_url(url).map_err(|e| anyhow::anyhow!("Invalid MQTT broker {}: {e}", config.broker))?;
+    let (client, mut event_loop) = AsyncClient::new(options, 16);
+    let filters = config.topics
+        .iter()
+        .map(|t| SubscribeFilter::new(t.filter.clone(), QoS::AtMostOnce))
+        .collect::<Vec<_>>();
+    println!("MQTT: {}", config.broker);
+
+    loop {
+        let event = match event_loop.poll().await {
+            Ok(event) => event,
+            Err(e) => {
+                println!("MQTT connection to {} failed: {e}", config.broker);
+                tokio::time::sleep(RECONNECT_AFTER).await;
+                continue;
+            }
+        };
+
+        match event {
+            // Subscriptions don't survive a reconnect with a clean session so are made on every connect
+            Event::Incoming(Packet::ConnAck(_)) => {
+                if let Err(e) = client.try_subscribe_many(filters.clone()) {
+                    println!("Error while subscribing to topics on {}: {e}", config.broker);
+                }
+            },
+            Event::Incoming(Packet::Publish(message)) => {
+                let Some(topic) = config.topics.iter().find(|t| match_topic(&t.filter, &message.topic).is_some()) else {
+                    continue;
+                };
+                let point = to_point(topic, &message.topic, &message.payload);
+                let result = match point {
+                    Ok((series, value)) => env.db.put_all(vec![(series, chrono::Utc::now().naive_utc(), value)]).await,
+                    Err(e) => Err(anyhow::anyhow!(e)),
+                };
+                if let Err(e) = result {
+                    println!("Ignoring MQTT message on {}: {e}", message.topic);
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Maps a message received on `topic` to a series name and value
+pub(crate) fn to_point(config: &MqttTopic, topic: &str, payload: &[u8]) -> Result<(String, f64), String> {
+    let wildcards = match_topic(&config.filter, topic).ok_or("Topic doesn't match the filter")?;
+    let name = match &config.series {
+        Some(template) => wildcards
+            .iter()
+            .enumerate()
+            .fold(template.clone(), |name, (i, level)| name.replace(&format!("{{{}}}", i + 1), level)),
+        None => topic.to_string(),
+    };
+    let series = to_series_name(&name).ok_or(format!("Invalid series name: {name}"))?;
+
+    let payload = std::str::from_utf8(payload).map_err(|_| "Payload isn't UTF-8")?;
+    let value = match &config.field {
+        Some(pointer) => {
+            let json = serde_json::from_str::<serde_json::Value>(payload).map_err(|e| format!("Invalid JSON: {e}"))?;
+            json_to_number(json.pointer(pointer).ok_or(format!("Missing field: {pointer}"))?)
+        },
+        None => payload.trim().parse::<f64>().ok(),
+    };
+
+    let value = value
+        .filter(|v| v.is_finite())
+        .ok_or(format!("Not a number: {payload}"))?;
+
+    Ok((series, value))
+}
+
+/// Matches a topic against a filter, returning the topic levels matched by each wildcard
+fn match_topic<'a>(filter: &str, topic: &'a str) -> Option<Vec<&'a str>> {
+    let mut wildcards = vec![];
+    let mut levels = topic.split('/');
+    let mut offset = 0;
+
+    for part in filter.split('/') {
+        if part == "#" {
+            wildcards.push(topic.get(offset..).unwrap_or_default());
+            return Some(wildcards);
+        }
+        let level = levels.next()?;
+        offset += level.len() + 1;
+        match part {
+            "+" => wildcards.push(level),
+            _ if part == level => {},
+            _ => return None,
+        }
+    }
+
+    match levels.next() {
+        None => Some(wildcards),
+        Some(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Reads an MQTT packet, returning its type and the bytes after the fixed header
+    async fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let packet_type = stream.read_u8().await.unwrap() >> 4;
+        let mut len = 0;
+        for shift in (0..28).step_by(7) {
+            let byte = stream.read_u8().await.unwrap();
+            len |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await.unwrap();
+        (packet_type, body)
+    }
+
+    #[test]
+    fn test_to_point() {
+        assert_eq!(match_topic("sensors/+/temperature", "sensors/kitchen/temperature"), Some(vec!["kitchen"]));
+        assert_eq!(match_topic("sensors/#", "sensors/kitchen/temperature"), Some(vec!["kitchen/temperature"]));
+        assert_eq!(match_topic("sensors/#", "sensors"), Some(vec![""]));
+        assert_eq!(match_topic("sensors/+", "sensors/kitchen/temperature"), None);
+        assert_eq!(match_topic("sensors/+/humidity", "sensors/kitchen/temperature"), None);
+
+        let topic = MqttTopic{ filter: "sensors/+/temperature".to_string(), series: Some("{1}_temp_c".to_string()), field: None };
+        assert_eq!(to_point(&topic, "sensors/Kitchen/temperature", b"21.5").unwrap(), ("kitchen_temp_c".to_string(), 21.5));
+        assert!(to_point(&topic, "sensors/kitchen/temperature", b"warm").is_err());
+
+        let topic = MqttTopic{ filter: "home/#".to_string(), series: None, field: Some("/power/watts".to_string()) };
+        assert_eq!(to_point(&topic, "home/meter", br#"{"power": {"watts": 230}}"#).unwrap(), ("home_meter".to_string(), 230.0));
+        assert_eq!(to_point(&topic, "home/meter", br#"{"power": {"watts": "231.5"}}"#).unwrap(), ("home_meter".to_string(), 231.5));
+        assert!(to_point(&topic, "home/meter", br#"{"power": {}}"#).is_err());
+        assert!(to_point(&topic, "home/meter", b"230").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_writes_published_messages() {
+        let broker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dir = std::env::temp_dir().join(format!("slapdash-mqtt-{}", std::process::id()));
+        let env = Environment::for_test(&dir).await;
+        let config = MqttCollector{
+            broker: format!("mqtt://{}", broker.local_addr().unwrap()),
+            client_id: None,
+            topics: vec![MqttTopic{ filter: "sensors/+/temperature".to_string(), series: Some("{1}_temp_c".to_string()), field: None }],
+        };
+        let task = tokio::spawn(run(config, env.clone()));
+
+        let (mut stream, _) = broker.accept().await.unwrap();
+        assert_eq!(read_packet(&mut stream).await.0, 1, "Expected CONNECT");
+        stream.write_all(&[0x20, 2, 0, 0]).await.unwrap();
+        let (packet_type, subscribe) = read_packet(&mut stream).await;
+        assert_eq!(packet_type, 8, "Expected SUBSCRIBE");
+        assert_eq!(&subscribe[4..4 + "sensors/+/temperature".len()], b"sensors/+/temperature");
+        stream.write_all(&[0x90, 3, subscribe[0], subscribe[1], 0]).await.unwrap();
+
+        let topic = b"sensors/kitchen/temperature";
+        let mut publish = vec![0x30, (2 + topic.len() + 4) as u8, 0, topic.len() as u8];
+        publish.extend_from_slice(topic);
+        publish.extend_from_slice(b"21.5");
+        stream.write_all(&publish).await.unwrap();
+
+        let mut point = None;
+        for _ in 0..50 {
+            point = env.db.get_last("kitchen_temp_c", None).await.unwrap();
+            if point.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(point.map(|p| p.value), Some(21.5));
+
+        task.abort();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
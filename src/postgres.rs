@@ -0,0 +1,514 @@
+use async_trait::async_trait;
+use std::path::Path;
+use chrono::NaiveDateTime;
+use sqlx::{PgConnection, PgPool};
+use crate::db;
+use crate::labels;
+use crate::model::{Bucket, Point, SeriesInfo, SeriesMetadata, TimeRange};
+use crate::storage::Storage;
+
+/// Stores points in Postgres so that several instances can share a database. If the TimescaleDB
+/// extension is installed the point table is made a hypertable.
+///
+/// Queries are checked at runtime rather than with the `query!` macros, so that building doesn't
+/// need a Postgres database.
+pub(crate) struct PostgresStorage(PgPool);
+
+impl PostgresStorage {
+    pub async fn connect(url: &str) -> anyhow::Result<Self> {
+        let pool = PgPool::connect(url).await?;
+        sqlx::migrate!("./migrations_postgres").run(&pool).await?;
+        label_series(&pool).await?;
+        Ok(Self(pool))
+    }
+}
+
+/// Replaces the labels of a series with those in its name
+async fn set_labels(db: &mut PgConnection, series_id: i64, series: &str) -> anyhow::Result<()> {
+    sqlx::query("DELETE FROM series_label WHERE series_id = $1")
+        .bind(series_id)
+        .execute(&mut *db)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to delete labels: {}", e))?;
+    for (key, value) in labels::labels_of(series).1 {
+        sqlx::query("INSERT INTO series_label (series_id, key, value) VALUES ($1, $2, $3)")
+            .bind(series_id)
+            .bind(key)
+            .bind(value)
+            .execute(&mut *db)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to insert label: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Stores the labels of series with labels in their names which have none stored, as series
+/// written before labels were stored don't
+async fn label_series(pool: &PgPool) -> anyhow::Result<()> {
+    let series = sqlx::query_as::<_, (i64, String)>("
+            SELECT id, name
+            FROM series
+            WHERE strpos(name, '{') > 0 AND id NOT IN (SELECT series_id FROM series_label)
+        ")
+        .fetch_all(pool)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to list series: {}", e))?;
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|_| anyhow::anyhow!("Failed to begin transaction"))?;
+    for (id, name) in series {
+        set_labels(&mut tx, id, &name).await?;
+    }
+    tx.commit().await.map_err(|_| anyhow::anyhow!("Failed to commit transaction"))?;
+
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn put(&self, series: &str, value: f64, time: Option<NaiveDateTime>) -> anyhow::Result<()> {
+        let time = time.unwrap_or(chrono::Utc::now().naive_utc());
+        self.put_all(vec![(series.to_string(), time, value)]).await
+    }
+
+    async fn put_all(&self, points: Vec<(String, NaiveDateTime, f64)>) -> anyhow::Result<()> {
+        let mut tx = self.0
+            .begin()
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to begin transaction"))?;
+
+        for (series, time, value) in points {
+            let inserted = sqlx::query_scalar::<_, i64>("INSERT INTO series (name) VALUES ($1) ON CONFLICT (name) DO NOTHING RETURNING id")
+                .bind(&series)
+                .fetch_optional(&mut *tx)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to insert series: {}", e))?;
+            if let Some(series_id) = inserted {
+                set_labels(&mut tx, series_id, &series).await?;
+            }
+
+            sqlx::query("INSERT INTO point (series_id, time, value) SELECT id, $2, $3 FROM series WHERE name = $1")
+                .bind(&series)
+                .bind(time.and_utc().timestamp_millis())
+                .bind(value)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to insert point: {}", e))?;
+        }
+
+        tx.commit().await.map_err(|_| anyhow::anyhow!("Failed to commit transaction"))?;
+
+        Ok(())
+    }
+
+    async fn get(&self, series: &str, range: TimeRange, buckets: i64) -> anyhow::Result<Vec<Bucket>> {
+        let (start, end) = range.millis();
+        let buckets = sqlx::query_as::<_, Bucket>("
+                WITH filtered AS (
+                    SELECT id, time, value
+                    FROM point
+                    WHERE series_id = (SELECT id FROM series WHERE name = $1)
+                        AND time > $2 AND time <= $4
+                ),
+                bucketed AS (
+                    SELECT f.id, f.time, f.value, f.time / $3 AS bucket
+                    FROM filtered f
+                ),
+                ranked AS (
+                    SELECT
+                        *,
+                        ROW_NUMBER() OVER (PARTITION BY bucket ORDER BY time ASC, id ASC) AS rn_first,
+                        ROW_NUMBER() OVER (PARTITION BY bucket ORDER BY time DESC, id DESC) AS rn_last
+                    FROM bucketed
+                )
+                SELECT
+                    to_timestamp(MIN(time) / 1000.0) AT TIME ZONE 'UTC' AS time,
+                    MAX(CASE WHEN rn_first = 1 THEN value END) AS first,
+                    MAX(CASE WHEN rn_last = 1 THEN value END) AS last,
+                    MIN(value) AS min,
+                    MAX(value) AS max,
+                    AVG(value) AS avg
+                FROM ranked
+                GROUP BY bucket
+                ORDER BY MIN(time) ASC
+            ")
+            .bind(series)
+            .bind(start)
+            .bind(db::bucket_millis(range.seconds, buckets))
+            .bind(end)
+            .fetch_all(&self.0)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to fetch points: {}", e))?;
+
+        Ok(buckets)
+    }
+
+    async fn get_last(&self, series: &str, until: Option<NaiveDateTime>) -> anyhow::Result<Option<Point>> {
+        let point = sqlx::query_as::<_, Point>("
+                SELECT to_timestamp(time / 1000.0) AT TIME ZONE 'UTC' AS time, value
+                FROM point
+                WHERE series_id = (SELECT id FROM series WHERE name = $1)
+                    AND time <= $2
+                ORDER BY time DESC, id DESC
+                LIMIT 1
+            ")
+            .bind(series)
+            .bind(until.map(|t| t.and_utc().timestamp_millis()).unwrap_or(i64::MAX))
+            .fetch_optional(&self.0)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to fetch last point: {}", e))?;
+
+        Ok(point)
+    }
+
+    async fn get_points(&self, series: &str, from: Option<NaiveDateTime>, to: Option<NaiveDateTime>) -> anyhow::Result<Vec<Point>> {
+        let points = sqlx::query_as::<_, Point>("
+                SELECT to_timestamp(time / 1000.0) AT TIME ZONE 'UTC' AS time, value
+                FROM point
+                WHERE series_id = (SELECT id FROM series WHERE name = $1)
+                    AND ($2::BIGINT IS NULL OR time >= $2)
+                    AND ($3::BIGINT IS NULL OR time < $3)
+                ORDER BY time ASC, id ASC
+            ")
+            .bind(series)
+            .bind(from.map(|t| t.and_utc().timestamp_millis()))
+            .bind(to.map(|t| t.and_utc().timestamp_millis()))
+            .fetch_all(&self.0)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to fetch points: {}", e))?;
+
+        Ok(points)
+    }
+
+    async fn list_series(&self) -> anyhow::Result<Vec<(i64, String)>> {
+        let series = sqlx::query_as::<_, (i64, String)>("SELECT id, name FROM series ORDER BY name")
+            .fetch_all(&self.0)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to list series: {}", e))?;
+
+        Ok(series)
+    }
+
+    async fn find_series(&self, name: &str, labels: Option<&[(String, String)]>) -> anyhow::Result<Vec<(i64, String)>> {
+        let series = sqlx::query_as::<_, (i64, String)>(r"
+                SELECT s.id, s.name
+                FROM series s
+                WHERE (
+                        (strpos(s.name, '{') = 0 AND s.name LIKE $1 ESCAPE '\')
+                        OR ($2::JSONB IS NOT NULL AND s.name LIKE $1 || '{%' ESCAPE '\')
+                    )
+                    AND (
+                        $2::JSONB IS NULL OR jsonb_array_length($2::JSONB) = 0 OR s.id IN (
+                            SELECT l.series_id
+                            FROM series_label l
+                            JOIN jsonb_array_elements($2::JSONB) m ON l.key = m->>0 AND l.value = m->>1
+                            GROUP BY l.series_id
+                            HAVING COUNT(*) = jsonb_array_length($2::JSONB)
+                        )
+                    )
+                ORDER BY s.name
+            ")
+            .bind(name)
+            .bind(labels.map(serde_json::to_string).transpose()?)
+            .fetch_all(&self.0)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to find series: {}", e))?;
+
+        Ok(series)
+    }
+
+    async fn delete_before(&self, series_id: i64, time: NaiveDateTime) -> anyhow::Result<u64> {
+        let result = sqlx::query("DELETE FROM point WHERE series_id = $1 AND time < $2")
+            .bind(series_id)
+            .bind(time.and_utc().timestamp_millis())
+            .execute(&self.0)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to delete points: {}", e))?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn list_series_info(&self, series: Option<&str>) -> anyhow::Result<Vec<SeriesInfo>> {
+        let series = sqlx::query_as::<_, SeriesInfo>("
+                SELECT
+                    s.name,
+                    COUNT(p.id) AS points,
+                    to_timestamp(MIN(p.time) / 1000.0) AT TIME ZONE 'UTC' AS first,
+                    to_timestamp(MAX(p.time) / 1000.0) AT TIME ZONE 'UTC' AS last
+                FROM series s
+                LEFT JOIN point p ON p.series_id = s.id
+                WHERE $1::TEXT IS NULL OR s.name = $1
+                GROUP BY s.id
+                ORDER BY s.name
+            ")
+            .bind(series)
+            .fetch_all(&self.0)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to list series: {}", e))?;
+
+        Ok(series)
+    }
+
+    async fn rename_series(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        let mut tx = self.0
+            .begin()
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to begin transaction"))?;
+
+        let exists = sqlx::query_scalar::<_, i64>("SELECT id FROM series WHERE name = $1")
+            .bind(to)
+            .fetch_optional(&mut *tx)
+            .await?;
+        if exists.is_some() {
+            return Err(anyhow::anyhow!("Series {to} already exists, merge the series instead"));
+        }
+
+        let series_id = sqlx::query_scalar::<_, i64>("UPDATE series SET name = $1 WHERE name = $2 RETURNING id")
+            .bind(to)
+            .bind(from)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to rename series: {}", e))?
+            .ok_or(anyhow::anyhow!("Series {from} not found"))?;
+        set_labels(&mut tx, series_id, to).await?;
+
+        tx.commit().await.map_err(|_| anyhow::anyhow!("Failed to commit transaction"))?;
+
+        Ok(())
+    }
+
+    async fn delete_series(&self, series: &str, from: Option<NaiveDateTime>, to: Option<NaiveDateTime>) -> anyhow::Result<u64> {
+        let mut tx = self.0
+            .begin()
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to begin transaction"))?;
+
+        let series_id = sqlx::query_scalar::<_, i64>("SELECT id FROM series WHERE name = $1")
+            .bind(series)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(anyhow::anyhow!("Series {series} not found"))?;
+
+        let result = sqlx::query("
+                DELETE FROM point
+                WHERE series_id = $1
+                    AND ($2::BIGINT IS NULL OR time >= $2)
+                    AND ($3::BIGINT IS NULL OR time < $3)
+            ")
+            .bind(series_id)
+            .bind(from.map(|t| t.and_utc().timestamp_millis()))
+            .bind(to.map(|t| t.and_utc().timestamp_millis()))
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to delete points: {}", e))?;
+
+        if from.is_none() && to.is_none() {
+            sqlx::query("DELETE FROM series_metadata WHERE series_id = $1")
+                .bind(series_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to delete metadata: {}", e))?;
+            sqlx::query("DELETE FROM series_label WHERE series_id = $1")
+                .bind(series_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to delete labels: {}", e))?;
+            sqlx::query("DELETE FROM series WHERE id = $1")
+                .bind(series_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to delete series: {}", e))?;
+        }
+
+        tx.commit().await.map_err(|_| anyhow::anyhow!("Failed to commit transaction"))?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn merge_series(&self, from: &str, into: &str) -> anyhow::Result<u64> {
+        if from == into {
+            return Err(anyhow::anyhow!("Cannot merge a series into itself"));
+        }
+
+        let mut tx = self.0
+            .begin()
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to begin transaction"))?;
+
+        let from_id = sqlx::query_scalar::<_, i64>("SELECT id FROM series WHERE name = $1")
+            .bind(from)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(anyhow::anyhow!("Series {from} not found"))?;
+
+        let inserted = sqlx::query_scalar::<_, i64>("INSERT INTO series (name) VALUES ($1) ON CONFLICT (name) DO NOTHING RETURNING id")
+            .bind(into)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to insert series: {}", e))?;
+        if let Some(series_id) = inserted {
+            set_labels(&mut tx, series_id, into).await?;
+        }
+
+        let into_id = sqlx::query_scalar::<_, i64>("SELECT id FROM series WHERE name = $1")
+            .bind(into)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        let result = sqlx::query("UPDATE point SET series_id = $1 WHERE series_id = $2")
+            .bind(into_id)
+            .bind(from_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to move points: {}", e))?;
+
+        sqlx::query("
+                UPDATE series_metadata SET series_id = $1
+                WHERE series_id = $2
+                    AND NOT EXISTS (SELECT 1 FROM series_metadata WHERE series_id = $1)
+            ")
+            .bind(into_id)
+            .bind(from_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to move metadata: {}", e))?;
+        sqlx::query("DELETE FROM series_metadata WHERE series_id = $1")
+            .bind(from_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to delete metadata: {}", e))?;
+        sqlx::query("DELETE FROM series_label WHERE series_id = $1")
+            .bind(from_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to delete labels: {}", e))?;
+
+        sqlx::query("DELETE FROM series WHERE id = $1")
+            .bind(from_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to delete series: {}", e))?;
+
+        tx.commit().await.map_err(|_| anyhow::anyhow!("Failed to commit transaction"))?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn get_metadata(&self, series: &str) -> anyhow::Result<SeriesMetadata> {
+        let metadata = sqlx::query_as::<_, SeriesMetadata>("
+                SELECT m.unit, m.description, m.decimals, m.min, m.max
+                FROM series_metadata m
+                JOIN series s ON s.id = m.series_id
+                WHERE s.name = $1
+            ")
+            .bind(series)
+            .fetch_optional(&self.0)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to fetch metadata: {}", e))?;
+
+        Ok(metadata.unwrap_or_default())
+    }
+
+    async fn set_metadata(&self, series: &str, metadata: &SeriesMetadata) -> anyhow::Result<()> {
+        let mut tx = self.0
+            .begin()
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to begin transaction"))?;
+
+        let inserted = sqlx::query_scalar::<_, i64>("INSERT INTO series (name) VALUES ($1) ON CONFLICT (name) DO NOTHING RETURNING id")
+            .bind(series)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to insert series: {}", e))?;
+        if let Some(series_id) = inserted {
+            set_labels(&mut tx, series_id, series).await?;
+        }
+
+        sqlx::query("
+                INSERT INTO series_metadata (series_id, unit, description, decimals, min, max)
+                SELECT id, $2, $3, $4, $5, $6 FROM series WHERE name = $1
+                ON CONFLICT (series_id) DO UPDATE SET
+                    unit = EXCLUDED.unit,
+                    description = EXCLUDED.description,
+                    decimals = EXCLUDED.decimals,
+                    min = EXCLUDED.min,
+                    max = EXCLUDED.max
+            ")
+            .bind(series)
+            .bind(&metadata.unit)
+            .bind(&metadata.description)
+            .bind(metadata.decimals)
+            .bind(metadata.min)
+            .bind(metadata.max)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to set metadata: {}", e))?;
+
+        tx.commit().await.map_err(|_| anyhow::anyhow!("Failed to commit transaction"))?;
+
+        Ok(())
+    }
+
+    /// Postgres reclaims the space of deleted rows with autovacuum
+    async fn vacuum(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn backup(&self, _path: &Path) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("Back up the Postgres database with pg_dump"))
+    }
+
+    /// Ranges are always read from the raw points
+    async fn roll_up(&self, _resolution: i64, _until: NaiveDateTime) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs against the Postgres database in `DATABASE_URL`, and is skipped when it isn't one. The
+    /// SQLite queries are checked against `.sqlx` meanwhile, e.g.
+    /// `SQLX_OFFLINE=true DATABASE_URL=postgres://localhost/slapdash_test cargo test postgres`
+    #[tokio::test]
+    async fn test_round_trip() {
+        let url = match std::env::var("DATABASE_URL") {
+            Ok(url) if url.starts_with("postgres") => url,
+            _ => {
+                println!("DATABASE_URL is not a Postgres database, skipping");
+                return;
+            }
+        };
+        let storage = PostgresStorage::connect(&url).await.unwrap();
+        let series = format!("test_round_trip_{}", std::process::id());
+        let other = format!("{series}_other");
+
+        let now = chrono::Utc::now().naive_utc();
+        let at = |seconds: i64| now - chrono::Duration::seconds(seconds);
+        storage.put(&series, 1.0, Some(at(50))).await.unwrap();
+        storage.put_all(vec![
+            (series.clone(), at(40), 2.0),
+            (other.clone(), at(35), 10.0),
+            (series.clone(), at(30), 3.0),
+        ]).await.unwrap();
+
+        let last = storage.get_last(&series, None).await.unwrap().unwrap();
+        assert_eq!(last.value, 3.0);
+        assert_eq!(last.x(), at(30).and_utc().timestamp_millis());
+        let before = storage.get_last(&series, Some(at(45))).await.unwrap().unwrap();
+        assert_eq!(before.value, 1.0);
+        assert!(storage.get_last(&series, Some(at(60))).await.unwrap().is_none());
+
+        let buckets = storage.get(&series, TimeRange::last(60), 60).await.unwrap();
+        assert_eq!(buckets.iter().map(|b| b.avg).collect::<Vec<_>>(), vec![1.0, 2.0, 3.0]);
+        assert_eq!(buckets[0].time.and_utc().timestamp_millis(), at(50).and_utc().timestamp_millis());
+        let buckets = storage.get(&other, TimeRange::last(60), 60).await.unwrap();
+        assert_eq!(buckets.iter().map(|b| b.avg).collect::<Vec<_>>(), vec![10.0]);
+
+        storage.delete_series(&series, None, None).await.unwrap();
+        storage.delete_series(&other, None, None).await.unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use chrono::{DateTime, NaiveDateTime};
+use regex::Regex;
+use crate::model::glob_to_regex;
+use crate::storage::Storage;
+use crate::env::Environment;
+
+/// How often expired points are deleted
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// The database is vacuumed to reclaim the space of deleted points at most this often
+const VACUUM_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A glob on series names and how long to keep points of matching series
+#[derive(Debug)]
+pub(crate) struct RetentionRule {
+    glob: String,
+    pattern: Regex,
+    max_age: chrono::Duration,
+}
+
+impl RetentionRule {
+    pub fn new(glob: &str, max_age: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            glob: glob.to_string(),
+            pattern: glob_to_regex(glob)?,
+            max_age: parse_duration(max_age).ok_or(anyhow::anyhow!("Invalid retention for {glob}: {max_age}, expected e.g. 30d"))?,
+        })
+    }
+}
+
+/// Returns the rule for a series. If several globs match the series the longest, most specific one applies.
+pub(crate) fn find_rule<'a>(rules: &'a [RetentionRule], series: &str) -> Option<&'a RetentionRule> {
+    rules
+        .iter()
+        .filter(|rule| rule.pattern.is_match(series))
+        .max_by_key(|rule| rule.glob.len())
+}
+
+/// Parses a duration such as `90s`, `15m`, `12h`, `30d`, `2w` or `1y`
+pub(crate) fn parse_duration(text: &str) -> Option<chrono::Duration> {
+    let text = text.trim();
+    let unit = text.chars().last()?;
+    let count = text[..text.len() - unit.len_utf8()].parse::<i64>().ok().filter(|c| *c > 0)?;
+    match unit {
+        's' => chrono::Duration::try_seconds(count),
+        'm' => chrono::Duration::try_minutes(count),
+        'h' => chrono::Duration::try_hours(count),
+        'd' => chrono::Duration::try_days(count),
+        'w' => chrono::Duration::try_weeks(count),
+        'y' => chrono::Duration::try_days(count.checked_mul(365)?),
+        _ => None,
+    }
+}
+
+/// Periodically deletes points which are older than the retention of their series. The first run
+/// is one `RETENTION_INTERVAL` after starting rather than immediately, as the server restarts this
+/// task whenever dashboards change.
+pub(crate) async fn run(retention: BTreeMap<String, String>, env: Environment) -> anyhow::Result<()> {
+    let rules = retention
+        .iter()
+        .map(|(glob, max_age)| RetentionRule::new(glob, max_age))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    println!("Retention: {}", retention.iter().map(|(glob, max_age)| format!("{glob}={max_age}")).collect::<Vec<_>>().join(", "));
+
+    let mut deleted_since_vacuum = 0;
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + RETENTION_INTERVAL, RETENTION_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let result = async {
+            deleted_since_vacuum += enforce(&*env.db, &rules).await?;
+
+            let now = chrono::Utc::now().naive_utc();
+            let vacuum_due = last_vacuum(&env.dir).is_none_or(|t| (now - t).to_std().is_ok_and(|elapsed| elapsed >= VACUUM_INTERVAL));
+            if deleted_since_vacuum > 0 && vacuum_due {
+                env.db.vacuum().await?;
+                println!("Retention: deleted {deleted_since_vacuum} expired points and vacuumed the database");
+                deleted_since_vacuum = 0;
+                std::fs::write(last_vacuum_path(&env.dir), now.and_utc().to_rfc3339())?;
+            }
+            anyhow::Ok(())
+        }.await;
+        if let Err(e) = result {
+            println!("Error while enforcing retention: {e}");
+        }
+    }
+}
+
+/// The time of the last vacuum is kept in the data directory so that it survives restarts
+fn last_vacuum_path(dir: &Path) -> PathBuf {
+    dir.join("last_vacuum")
+}
+
+fn last_vacuum(dir: &Path) -> Option<NaiveDateTime> {
+    let text = std::fs::read_to_string(last_vacuum_path(dir)).ok()?;
+    DateTime::parse_from_rfc3339(text.trim()).ok().map(|t| t.naive_utc())
+}
+
+/// Deletes expired points, returning how many were deleted
+async fn enforce(db: &dyn Storage, rules: &[RetentionRule]) -> anyhow::Result<u64> {
+    let now = chrono::Utc::now().naive_utc();
+    let mut deleted = 0;
+    for (series_id, series) in db.list_series().await? {
+        if let Some(rule) = find_rule(rules, &series) {
+            deleted += db.delete_before(series_id, now - rule.max_age).await?;
+        }
+    }
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_rule() {
+        assert_eq!(parse_duration("30d"), Some(chrono::Duration::days(30)));
+        assert_eq!(parse_duration("1y"), Some(chrono::Duration::days(365)));
+        assert_eq!(parse_duration("12h"), Some(chrono::Duration::hours(12)));
+        assert_eq!(parse_duration("30"), None);
+        assert_eq!(parse_duration("0d"), None);
+        assert_eq!(parse_duration("d"), None);
+
+        let rules = vec![
+            RetentionRule::new("*", "1y").unwrap(),
+            RetentionRule::new("host_*", "30d").unwrap(),
+            RetentionRule::new("host_cpu_load_?m", "7d").unwrap(),
+        ];
+        assert_eq!(find_rule(&rules, "solar_power").unwrap().glob, "*");
+        assert_eq!(find_rule(&rules, "host_mem_used_pct").unwrap().glob, "host_*");
+        assert_eq!(find_rule(&rules, "host_cpu_load_1m").unwrap().glob, "host_cpu_load_?m");
+        assert_eq!(find_rule(&rules, "host_cpu_load_15m").unwrap().glob, "host_*");
+        assert!(find_rule(&rules[1..], "solar_power").is_none());
+        assert!(RetentionRule::new("*", "forever").is_err());
+    }
+}
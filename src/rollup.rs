@@ -0,0 +1,132 @@
+use std::time::Duration;
+use crate::db;
+use crate::env::Environment;
+
+/// How often new points are rolled up
+const ROLLUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Periodically downsamples points into the hourly and daily rollups used for long ranges
+pub(crate) async fn run(env: Environment) -> anyhow::Result<()> {
+    let mut ticker = tokio::time::interval(ROLLUP_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let result = async {
+            let now = chrono::Utc::now().naive_utc();
+            for resolution in db::ROLLUP_RESOLUTIONS {
+                env.db.roll_up(resolution, now).await?;
+            }
+            anyhow::Ok(())
+        }.await;
+        if let Err(e) = result {
+            println!("Error while rolling up points: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use sqlx::Connection;
+    use crate::model::TimeRange;
+
+    #[tokio::test]
+    async fn test_roll_up() {
+        let mut db = sqlx::SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!("./migrations").run(&mut db).await.unwrap();
+
+        let hour = chrono::Duration::hours(1);
+        let now = chrono::DateTime::from_timestamp(1_790_001_234, 0).unwrap().naive_utc();
+        let start = chrono::DateTime::from_timestamp(now.and_utc().timestamp() / 3600 * 3600, 0).unwrap().naive_utc() - hour * 30 * 24;
+        let points = (0..30 * 24 * 4)
+            .map(|i| ("power".to_string(), start + chrono::Duration::minutes(15 * i), i as f64))
+            .collect();
+        db::put_all(&mut db, points, &mut HashMap::new()).await.unwrap();
+        db::roll_up(&mut db, 3600, now).await.unwrap();
+
+        let buckets = sqlx::query!(r#"SELECT count as "count!: i64", sum, min, max, first, last FROM rollup WHERE resolution = 3600 ORDER BY time"#)
+            .fetch_all(&mut db)
+            .await
+            .unwrap();
+        assert_eq!(buckets.len(), 30 * 24);
+        assert_eq!((buckets[0].count, buckets[0].sum, buckets[0].min, buckets[0].max, buckets[0].first, buckets[0].last), (4, 6.0, 0.0, 3.0, 0.0, 3.0));
+
+        // A late point is merged into its bucket
+        db::put_all(&mut db, vec![("power".to_string(), start + chrono::Duration::minutes(50), -1.0)], &mut HashMap::new()).await.unwrap();
+        db::roll_up(&mut db, 3600, now).await.unwrap();
+        let bucket = sqlx::query!(r#"SELECT count as "count!: i64", min, last FROM rollup WHERE resolution = 3600 ORDER BY time LIMIT 1"#)
+            .fetch_one(&mut db)
+            .await
+            .unwrap();
+        assert_eq!((bucket.count, bucket.min, bucket.last), (5, -1.0, -1.0));
+
+        // A 30 day range is read from the hourly rollup in 2 hour buckets. The first hour is just
+        // outside the range and the next hour, an odd one, is alone in the first bucket
+        assert_eq!(db::bucket_millis(30 * 24 * 3600, 512), 2 * 3600 * 1000);
+        let buckets = db::get(&mut db, "power", TimeRange{ end: Some(now), seconds: 30 * 24 * 3600 }, 512).await.unwrap();
+        assert!(buckets.len() <= 512);
+        let first = &buckets[0];
+        assert_eq!((first.first, first.last, first.min, first.max, first.avg), (4.0, 7.0, 4.0, 7.0, 5.5));
+        assert!(buckets.iter().all(|b| b.first as i64 % 4 == 0));
+    }
+
+    #[tokio::test]
+    async fn test_rollups_follow_merge_and_delete() {
+        let mut db = sqlx::SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!("./migrations").run(&mut db).await.unwrap();
+
+        let now = chrono::Utc::now().naive_utc();
+        let start = chrono::DateTime::from_timestamp(now.and_utc().timestamp() / 3600 * 3600, 0).unwrap().naive_utc() - chrono::Duration::hours(2);
+        let at = |minutes| start + chrono::Duration::minutes(minutes);
+        let mut series_ids = HashMap::new();
+        db::put_all(&mut db, vec![
+            ("old".to_string(), at(0), 1.0),
+            ("new".to_string(), at(30), 5.0),
+            ("new".to_string(), at(60), 7.0),
+        ], &mut series_ids).await.unwrap();
+        db::roll_up(&mut db, 3600, now).await.unwrap();
+
+        assert_eq!(db::merge_series(&mut db, "old", "new").await.unwrap(), 1);
+        let rollups = sqlx::query!(r#"SELECT count as "count!: i64", sum, first FROM rollup WHERE resolution = 3600 ORDER BY time"#)
+            .fetch_all(&mut db)
+            .await
+            .unwrap();
+        assert_eq!(rollups.iter().map(|r| (r.count, r.sum, r.first)).collect::<Vec<_>>(), vec![(2, 6.0, 1.0), (1, 7.0, 7.0)]);
+
+        assert_eq!(db::delete_series(&mut db, "new", Some(at(15)), None).await.unwrap(), 2);
+        let rollups = sqlx::query!(r#"SELECT count as "count!: i64", sum FROM rollup WHERE resolution = 3600 ORDER BY time"#)
+            .fetch_all(&mut db)
+            .await
+            .unwrap();
+        assert_eq!(rollups.iter().map(|r| (r.count, r.sum)).collect::<Vec<_>>(), vec![(1, 1.0)]);
+
+        let series = db::list_series_info(&mut db, None).await.unwrap();
+        assert_eq!(series.iter().map(|s| (s.name.as_str(), s.points)).collect::<Vec<_>>(), vec![("new", 1)]);
+
+        // The cached id of the merged series isn't reused
+        db::put_all(&mut db, vec![("old".to_string(), at(90), 2.0)], &mut series_ids).await.unwrap();
+        let series = db::list_series_info(&mut db, None).await.unwrap();
+        assert_eq!(series.iter().map(|s| (s.name.as_str(), s.points)).collect::<Vec<_>>(), vec![("new", 1), ("old", 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_delete_before_keeps_the_bucket_it_splits() {
+        let mut db = sqlx::SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!("./migrations").run(&mut db).await.unwrap();
+
+        let now = chrono::Utc::now().naive_utc();
+        let start = chrono::DateTime::from_timestamp(now.and_utc().timestamp() / 3600 * 3600, 0).unwrap().naive_utc() - chrono::Duration::hours(3);
+        let at = |minutes| start + chrono::Duration::minutes(minutes);
+        let mut series_ids = HashMap::new();
+        db::put_all(&mut db, (0..8).map(|i| ("power".to_string(), at(15 * i), i as f64)).collect(), &mut series_ids).await.unwrap();
+        db::roll_up(&mut db, 3600, now).await.unwrap();
+
+        // The cutoff falls in the second hour, whose two later points are kept
+        assert_eq!(db::delete_before(&mut db, series_ids["power"], at(90)).await.unwrap(), 6);
+        let rollups = sqlx::query!(r#"SELECT count as "count!: i64", sum, first FROM rollup WHERE resolution = 3600 ORDER BY time"#)
+            .fetch_all(&mut db)
+            .await
+            .unwrap();
+        assert_eq!(rollups.iter().map(|r| (r.count, r.sum, r.first)).collect::<Vec<_>>(), vec![(2, 13.0, 6.0)]);
+    }
+}
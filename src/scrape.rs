@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use chrono::{DateTime, NaiveDateTime};
+use regex::Regex;
+use crate::env::{Environment, ScrapeTarget};
+use crate::model::to_series_name;
+
+const DEFAULT_SCRAPE_INTERVAL_SECONDS: u64 = 60;
+
+/// Periodically scrapes a Prometheus `/metrics` endpoint and writes its gauges and counters
+pub(crate) async fn run(target: ScrapeTarget, env: Environment) -> anyhow::Result<()> {
+    let interval = Duration::from_secs(target.interval.unwrap_or(DEFAULT_SCRAPE_INTERVAL_SECONDS).max(1));
+    let filter = target.filter.as_deref().map(Regex::new).transpose()?;
+    let client = reqwest::Client::builder().timeout(interval).build()?;
+    println!("Scraping: {} every {}s", target.url, interval.as_secs());
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let result = async {
+            let points = scrape(&client, &target, filter.as_ref()).await?;
+            env.db.put_all(points).await
+        }.await;
+        if let Err(e) = result {
+            println!("Error while scraping {}: {e}", target.url);
+        }
+    }
+}
+
+pub(crate) async fn scrape(client: &reqwest::Client, target: &ScrapeTarget, filter: Option<&Regex>) -> anyhow::Result<Vec<(String, NaiveDateTime, f64)>> {
+    let body = client
+        .get(&target.url)
+        .header(reqwest::header::ACCEPT, "text/plain;version=0.0.4")
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+
+    let samples = parse(&body).map_err(|e| anyhow::anyhow!(e))?;
+    let now = chrono::Utc::now().naive_utc();
+
+    let points = samples
+        .into_iter()
+        .filter(|s| filter.is_none_or(|f| f.is_match(&s.name)))
+        .filter_map(|s| {
+            let series = s.series_name(&target.name)?;
+            let time = s.timestamp_ms
+                .and_then(DateTime::from_timestamp_millis)
+                .map(|t| t.naive_utc())
+                .unwrap_or(now);
+            Some((series, time, s.value))
+        })
+        .collect();
+
+    Ok(points)
+}
+
+type Labels = Vec<(String, String)>;
+
+/// A sample from the Prometheus text exposition format
+#[derive(Debug, PartialEq)]
+pub(crate) struct Sample {
+    pub name: String,
+    pub labels: Labels,
+    pub value: f64,
+    pub timestamp_ms: Option<i64>,
+}
+
+impl Sample {
+    /// The series name is made of the target name, the metric name and the label values ordered
+    /// by label name.
+    fn series_name(&self, target: &str) -> Option<String> {
+        let mut labels = self.labels.iter().collect::<Vec<_>>();
+        labels.sort();
+
+        let mut parts = vec![target, self.name.as_str()];
+        parts.extend(labels.iter().map(|(_, value)| value.as_str()));
+        to_series_name(&parts.join("_"))
+    }
+}
+
+/// Parses the Prometheus text exposition format, keeping only finite samples of gauges, counters
+/// and untyped metrics. Histograms and summaries are skipped.
+pub(crate) fn parse(text: &str) -> Result<Vec<Sample>, String> {
+    let mut types = HashMap::new();
+    let mut samples = vec![];
+
+    for (line_num, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(comment) = line.strip_prefix('#') {
+            let mut words = comment.split_whitespace();
+            if let (Some("TYPE"), Some(name), Some(typ)) = (words.next(), words.next(), words.next()) {
+                types.insert(name.to_string(), typ.to_string());
+            }
+            continue;
+        }
+
+        let sample = parse_sample(line).map_err(|e| format!("At line {}. {e}", line_num + 1))?;
+
+        let family_type = types.get(&sample.name).map(String::as_str).or_else(|| {
+            ["_bucket", "_sum", "_count"]
+                .iter()
+                .find_map(|suffix| sample.name.strip_suffix(suffix))
+                .and_then(|family| types.get(family).map(String::as_str))
+        });
+
+        let keep = matches!(family_type, None | Some("gauge") | Some("counter") | Some("untyped"));
+        if keep && sample.value.is_finite() {
+            samples.push(sample);
+        }
+    }
+
+    Ok(samples)
+}
+
+fn parse_sample(line: &str) -> Result<Sample, String> {
+    let name_end = line.find(['{', ' ', '\t']).ok_or("Missing value")?;
+    let name = line[..name_end].to_string();
+    let mut rest = &line[name_end..];
+
+    let mut labels = vec![];
+    if let Some(label_text) = rest.strip_prefix('{') {
+        let (parsed, remainder) = parse_labels(label_text)?;
+        labels = parsed;
+        rest = remainder;
+    }
+
+    let mut parts = rest.split_whitespace();
+    let value = parts.next().ok_or("Missing value")?;
+    let value = match value {
+        "+Inf" => f64::INFINITY,
+        "-Inf" => f64::NEG_INFINITY,
+        "NaN" => f64::NAN,
+        _ => value.parse::<f64>().map_err(|_| format!("Invalid value: {value}"))?,
+    };
+    let timestamp_ms = parts
+        .next()
+        .map(|t| t.parse::<i64>().map_err(|_| format!("Invalid timestamp: {t}")))
+        .transpose()?;
+
+    Ok(Sample{ name, labels, value, timestamp_ms })
+}
+
+/// Parses `name="value",...}` returning the labels and the text following the closing brace
+fn parse_labels(text: &str) -> Result<(Labels, &str), String> {
+    let mut labels = vec![];
+    let mut rest = text.trim_start();
+
+    loop {
+        if let Some(remainder) = rest.strip_prefix('}') {
+            return Ok((labels, remainder));
+        }
+
+        let (name, remainder) = rest.split_once('=').ok_or("Invalid label")?;
+        let remainder = remainder.trim_start().strip_prefix('"').ok_or("Label value must be quoted")?;
+
+        let mut value = String::new();
+        let mut chars = remainder.char_indices();
+        let end = loop {
+            match chars.next() {
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, c)) => value.push(c),
+                    None => return Err("Unterminated label value".to_string()),
+                },
+                Some((i, '"')) => break i,
+                Some((_, c)) => value.push(c),
+                None => return Err("Unterminated label value".to_string()),
+            }
+        };
+
+        labels.push((name.trim().trim_start_matches(',').trim().to_string(), value));
+        rest = remainder[end + 1..].trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, routing::get};
+
+    const METRICS: &str = r#"# HELP node_load1 1m load average.
+# TYPE node_load1 gauge
+node_load1 0.21
+# TYPE node_cpu_seconds_total counter
+node_cpu_seconds_total{cpu="0",mode="idle"} 12345.5
+node_cpu_seconds_total{mode="user",cpu="0"} 42 1718292600000
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="0.1"} 3
+http_request_duration_seconds_sum 0.5
+http_request_duration_seconds_count 3
+node_filesystem_label{path="C:\\data, \"x\""} NaN
+up 1
+"#;
+
+    #[test]
+    fn test_parse() {
+        let samples = parse(METRICS).unwrap();
+        assert_eq!(samples, vec![
+            Sample{ name: "node_load1".to_string(), labels: vec![], value: 0.21, timestamp_ms: None },
+            Sample{ name: "node_cpu_seconds_total".to_string(), labels: vec![("cpu".to_string(), "0".to_string()), ("mode".to_string(), "idle".to_string())], value: 12345.5, timestamp_ms: None },
+            Sample{ name: "node_cpu_seconds_total".to_string(), labels: vec![("mode".to_string(), "user".to_string()), ("cpu".to_string(), "0".to_string())], value: 42.0, timestamp_ms: Some(1718292600000) },
+            Sample{ name: "up".to_string(), labels: vec![], value: 1.0, timestamp_ms: None },
+        ]);
+
+        assert!(parse("node_load1").is_err());
+        assert!(parse("node_load1{mode=\"idle} 1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_scrape() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/metrics", get(|| async { METRICS }));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let target = ScrapeTarget{ name: "pi".to_string(), url: format!("http://{addr}/metrics"), interval: None, filter: None };
+        let filter = Regex::new("^node_").unwrap();
+        let points = scrape(&reqwest::Client::new(), &target, Some(&filter)).await.unwrap();
+
+        let names = points.iter().map(|(series, _, _)| series.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["pi_node_load1", "pi_node_cpu_seconds_total_0_idle", "pi_node_cpu_seconds_total_0_user"]);
+        assert_eq!(points[2].1, DateTime::from_timestamp(1_718_292_600, 0).unwrap().naive_utc());
+    }
+}
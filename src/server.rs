@@ -1,43 +1,147 @@
 use crate::env::Dashboards;
 use crate::env::Environment;
-use crate::controller;
+use crate::{controller, exec, graphite, host, mqtt, retention, rollup, scrape, statsd};
+use std::future::Future;
+use anyhow::anyhow;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
 use axum::{
     Router,
-    routing::get,
+    routing::{get, post},
 };
+use tokio::sync::oneshot;
+use tokio::task::{JoinHandle, JoinSet};
+
+const DEFAULT_STATSD_FLUSH_SECONDS: u64 = 10;
 
 pub struct Server;
 
 impl Server{
-    pub async fn serve(listen_addr: &Option<SocketAddr>, secret: &Option<String>) -> anyhow::Result<()> {
-        let (_watcher, mut dashboards_changed_rx) = Dashboards::watch()?;
+    pub async fn serve(dir: &Path, listen_addr: &Option<SocketAddr>, secret: &Option<String>) -> anyhow::Result<()> {
+        let _lock = Environment::lock(dir)?.ok_or(anyhow!("Another server is running with {}", dir.display()))?;
+        let (_watcher, mut dashboards_changed_rx) = Dashboards::watch(dir)?;
+
+        // The ingest queue runs until the server shuts down, as connections kept open across a
+        // reload keep writing through the environment they started with
+        let mut env = Environment::load(dir).await?;
+        let mut ingest = vec![];
+        spawn_stoppable(&mut ingest, "Ingest queue", |stop| env.db.start_queue(stop));
 
         loop{
-            let env = Environment::load().await?;
             let secret = secret.as_ref().unwrap_or(&env.settings.secret).to_string();
             let listen_addr = listen_addr.unwrap_or(env.settings.listen_addr);
             let dashboard_list = env.dashboards.list();
+            let webhook_list = env.collectors.webhooks.iter().map(|w| w.name.clone()).collect::<Vec<_>>();
+
+            // Background tasks are aborted by dropping the set when the dashboards change. The
+            // stoppable tasks are then told to stop and waited for.
+            let mut tasks = JoinSet::new();
+            let mut stoppable = vec![];
+            if let Some(statsd_addr) = env.settings.statsd_addr {
+                let flush_interval = Duration::from_secs(env.settings.statsd_flush_seconds.unwrap_or(DEFAULT_STATSD_FLUSH_SECONDS).max(1));
+                let env = env.clone();
+                spawn_stoppable(&mut stoppable, "StatsD listener", |stop| statsd::listen(statsd_addr, flush_interval, env, stop));
+            }
+            if let Some(graphite_addr) = env.settings.graphite_addr {
+                spawn_task(&mut tasks, "Graphite listener", graphite::listen(graphite_addr, env.clone()));
+            }
+            spawn_task(&mut tasks, "Rollup", rollup::run(env.clone()));
+            if !env.settings.retention.is_empty() {
+                spawn_task(&mut tasks, "Retention", retention::run(env.settings.retention.clone(), env.clone()));
+            }
+            for target in env.collectors.scrape.iter() {
+                spawn_task(&mut tasks, "Prometheus scraper", scrape::run(target.clone(), env.clone()));
+            }
+            if let Some(host_collector) = env.collectors.host.clone() {
+                spawn_task(&mut tasks, "Host metrics collector", host::run(host_collector, env.clone()));
+            }
+            for exec_collector in env.collectors.exec.iter() {
+                spawn_task(&mut tasks, "Exec collector", exec::run(exec_collector.clone(), env.clone()));
+            }
+            for mqtt_collector in env.collectors.mqtt.iter() {
+                spawn_task(&mut tasks, "MQTT subscriber", mqtt::run(mqtt_collector.clone(), env.clone()));
+            }
 
             let app = Router::new()
                 .route("/", get(controller::get_default))
                 .route("/{dashboard}", get(controller::get))
                 .route("/{secret}/{series}/{value}", get(controller::put))
-                .with_state(env);
+                .route("/{secret}/batch", post(controller::put_batch))
+                .route("/{secret}/export", get(controller::export))
+                .route("/{secret}/series/{series}/metadata", get(controller::get_metadata).put(controller::put_metadata))
+                .route("/{secret}/api/series", get(controller::list_series))
+                .route("/{secret}/api/series/{series}", get(controller::get_series))
+                .route("/{secret}/api/latest", get(controller::get_latest))
+                .route("/write", post(controller::write_influx))
+                .route("/api/v2/write", post(controller::write_influx))
+                .route("/webhook/{name}", post(controller::receive_webhook))
+                .with_state(env.clone());
 
             let listener = tokio::net::TcpListener::bind(listen_addr).await?;
         
             println!("Serving at: http://{listen_addr}/(<dashboard>)");
             println!("Dashboards:\n\t{}", &dashboard_list.join("\n\t"));
             println!("Push data: GET http://{}/{}/<series>/<value>", listen_addr, &secret);
-
-            tokio::select! {
-                _ = dashboards_changed_rx.recv() => {}
-                _ = axum::serve(listener, app) => {}
+            println!("Push batch: POST http://{}/{}/batch", listen_addr, &secret);
+            println!("Read API: GET http://{}/{}/api/series", listen_addr, &secret);
+            println!("InfluxDB line protocol: POST http://{}/write", listen_addr);
+            for webhook in webhook_list.iter() {
+                println!("Webhook: POST http://{}/webhook/{}", listen_addr, webhook);
             }
 
+            let shutdown = tokio::select! {
+                _ = dashboards_changed_rx.recv() => false,
+                _ = axum::serve(listener, app) => false,
+                _ = tokio::signal::ctrl_c() => true,
+            };
+
+            drop(tasks);
+            stop(stoppable).await;
+            if shutdown {
+                // The ingest queue stops last, committing the points written by the other tasks
+                stop(ingest).await;
+                println!("Stopped");
+                return Ok(());
+            }
             println!("Dashboards changed, reloading..");
+            env = env.reload()?;
         }
     }
 }
 
+fn spawn_task(tasks: &mut JoinSet<()>, name: &'static str, task: impl Future<Output = anyhow::Result<()>> + Send + 'static) {
+    tasks.spawn(async move {
+        if let Err(e) = task.await {
+            println!("{name} stopped: {e}");
+        }
+    });
+}
+
+/// A background task which is told to stop, so it can finish its work, rather than being aborted
+struct StoppableTask {
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+fn spawn_stoppable<F>(stoppable: &mut Vec<StoppableTask>, name: &'static str, task: impl FnOnce(oneshot::Receiver<()>) -> F)
+where
+    F: Future<Output = anyhow::Result<()>> + Send + 'static
+{
+    let (stop, stop_rx) = oneshot::channel();
+    let task = task(stop_rx);
+    let handle = tokio::spawn(async move {
+        if let Err(e) = task.await {
+            println!("{name} stopped: {e}");
+        }
+    });
+    stoppable.push(StoppableTask{ stop, handle });
+}
+
+/// Stops the tasks in the reverse of the order they were started in, waiting for each to finish
+async fn stop(stoppable: Vec<StoppableTask>) {
+    for task in stoppable.into_iter().rev() {
+        let _ = task.stop.send(());
+        let _ = task.handle.await;
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::Duration;
+use chrono::NaiveDateTime;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use crate::env::Environment;
+use crate::model::to_series_name;
+
+/// Receives StatsD metrics over UDP and writes them to the database once per `flush_interval`.
+/// The metrics received since the last flush are written when `stop` fires.
+pub(crate) async fn listen(addr: SocketAddr, flush_interval: Duration, env: Environment, mut stop: oneshot::Receiver<()>) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind(addr).await?;
+    println!("StatsD: UDP {addr}");
+
+    let mut aggregator = Aggregator::default();
+    let mut flush = tokio::time::interval(flush_interval);
+    let mut buf = vec![0u8; 65535];
+
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let len = match received {
+                    Ok((len, _)) => len,
+                    Err(e) => {
+                        println!("Error while receiving StatsD metrics: {e}");
+                        continue;
+                    }
+                };
+                let packet = String::from_utf8_lossy(&buf[..len]);
+                for line in packet.lines().filter(|l| !l.trim().is_empty()) {
+                    if let Err(e) = aggregator.add(line) {
+                        println!("Ignoring StatsD line '{line}': {e}");
+                    }
+                }
+            }
+            _ = flush.tick() => write(&mut aggregator, &env).await,
+            _ = &mut stop => {
+                write(&mut aggregator, &env).await;
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn write(aggregator: &mut Aggregator, env: &Environment) {
+    let points = aggregator.flush(chrono::Utc::now().naive_utc());
+    if points.is_empty() {
+        return;
+    }
+    let result = env.db.put_all(points).await;
+    if let Err(e) = result {
+        println!("Error while writing StatsD metrics: {e}");
+    }
+}
+
+/// Accumulates StatsD metrics between flushes.
+///
+/// Gauges keep their value across flushes so that `+`/`-` deltas can be applied, but are only
+/// written when they were updated. Counters are summed, and written as 0 once seen if they
+/// weren't incremented since the last flush. Timers are summarized as
+/// `<name>_count`, `<name>_mean`, `<name>_min` and `<name>_max`.
+#[derive(Default)]
+pub(crate) struct Aggregator {
+    gauges: HashMap<String, f64>,
+    updated_gauges: HashSet<String>,
+    counters: HashMap<String, f64>,
+    timers: HashMap<String, Vec<f64>>,
+}
+
+impl Aggregator {
+    /// Adds a line of the form `name:value|type[|@sample_rate][|#tags]`
+    pub fn add(&mut self, line: &str) -> Result<(), String> {
+        let (name, rest) = line.trim().split_once(':').ok_or("Expected name:value|type")?;
+        let series = to_series_name(name).ok_or("Invalid metric name")?;
+
+        let mut parts = rest.split('|');
+        let value = parts.next().unwrap_or_default();
+        let typ = parts.next().ok_or("Missing metric type")?;
+        let sample_rate = parts
+            .find_map(|p| p.strip_prefix('@'))
+            .map(|r| r.parse::<f64>().map_err(|_| format!("Invalid sample rate: {r}")))
+            .transpose()?
+            .filter(|r| *r > 0.0 && *r <= 1.0)
+            .unwrap_or(1.0);
+
+        let number = value.parse::<f64>().map_err(|_| format!("Invalid value: {value}"))?;
+        if !number.is_finite() {
+            return Err(format!("Invalid value: {value}"));
+        }
+
+        match typ {
+            "g" => {
+                let gauge = self.gauges.entry(series.clone()).or_default();
+                if value.starts_with(['+', '-']) {
+                    *gauge += number;
+                } else {
+                    *gauge = number;
+                }
+                self.updated_gauges.insert(series);
+            },
+            "c" => *self.counters.entry(series).or_default() += number / sample_rate,
+            "ms" | "h" => self.timers.entry(series).or_default().push(number),
+            other => return Err(format!("Unsupported metric type: {other}")),
+        }
+
+        Ok(())
+    }
+
+    /// Returns the points accumulated since the last flush, all stamped with `time`
+    pub fn flush(&mut self, time: NaiveDateTime) -> Vec<(String, NaiveDateTime, f64)> {
+        let mut points = vec![];
+
+        for series in self.updated_gauges.drain() {
+            points.push((series.clone(), time, self.gauges[&series]));
+        }
+
+        for (series, count) in self.counters.iter_mut() {
+            points.push((series.clone(), time, std::mem::take(count)));
+        }
+
+        for (series, samples) in self.timers.drain() {
+            let count = samples.len() as f64;
+            let mean = samples.iter().sum::<f64>() / count;
+            let min = samples.iter().cloned().fold(f64::INFINITY, f64::min);
+            let max = samples.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            points.push((format!("{series}_count"), time, count));
+            points.push((format!("{series}_mean"), time, mean));
+            points.push((format!("{series}_min"), time, min));
+            points.push((format!("{series}_max"), time, max));
+        }
+
+        points
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregator() {
+        let time = chrono::DateTime::from_timestamp(1_718_292_600, 0).unwrap().naive_utc();
+        let mut aggregator = Aggregator::default();
+
+        aggregator.add("battery.voltage:12.5|g").unwrap();
+        aggregator.add("battery.voltage:-0.5|g").unwrap();
+        aggregator.add("pump.starts:1|c").unwrap();
+        aggregator.add("pump.starts:1|c|@0.5").unwrap();
+        aggregator.add("wifi.connect:100|ms").unwrap();
+        aggregator.add("wifi.connect:300|ms|#ssid:home").unwrap();
+        assert!(aggregator.add("broken").is_err());
+        assert!(aggregator.add("pump.starts:x|c").is_err());
+        assert!(aggregator.add("pump.starts:1|s").is_err());
+
+        let mut points = aggregator.flush(time);
+        points.sort_by(|a, b| a.0.cmp(&b.0));
+        let points = points.into_iter().map(|(series, _, value)| (series, value)).collect::<Vec<_>>();
+        assert_eq!(points, vec![
+            ("battery_voltage".to_string(), 12.0),
+            ("pump_starts".to_string(), 3.0),
+            ("wifi_connect_count".to_string(), 2.0),
+            ("wifi_connect_max".to_string(), 300.0),
+            ("wifi_connect_mean".to_string(), 200.0),
+            ("wifi_connect_min".to_string(), 100.0),
+        ]);
+
+        assert_eq!(aggregator.flush(time), vec![("pump_starts".to_string(), time, 0.0)]);
+        aggregator.add("battery.voltage:+1|g").unwrap();
+        assert_eq!(aggregator.flush(time)[0], ("battery_voltage".to_string(), time, 13.0));
+    }
+}
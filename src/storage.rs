@@ -0,0 +1,172 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use chrono::NaiveDateTime;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous};
+use tokio::sync::Mutex;
+use crate::db;
+use crate::model::{Bucket, Point, SeriesInfo, SeriesMetadata, TimeRange};
+
+/// Where points are persisted. SQLite is the default, Postgres allows several instances to share
+/// one database.
+#[async_trait]
+pub(crate) trait Storage: Send + Sync {
+    /// Writes a point, stamped with the current time if `time` is not given
+    async fn put(&self, series: &str, value: f64, time: Option<NaiveDateTime>) -> anyhow::Result<()>;
+
+    /// Writes many points in a single transaction
+    async fn put_all(&self, points: Vec<(String, NaiveDateTime, f64)>) -> anyhow::Result<()>;
+
+    /// Returns up to `buckets` buckets of a series over the range
+    async fn get(&self, series: &str, range: TimeRange, buckets: i64) -> anyhow::Result<Vec<Bucket>>;
+
+    /// Returns the last point of a series, or the last at or before `until` if given
+    async fn get_last(&self, series: &str, until: Option<NaiveDateTime>) -> anyhow::Result<Option<Point>>;
+
+    /// Returns the raw points of a series at or after `from` and before `to`, oldest first
+    async fn get_points(&self, series: &str, from: Option<NaiveDateTime>, to: Option<NaiveDateTime>) -> anyhow::Result<Vec<Point>>;
+
+    /// Returns the id and name of every series
+    async fn list_series(&self) -> anyhow::Result<Vec<(i64, String)>>;
+
+    /// Returns the id and name of the series whose names without labels are like `name`, a SQL
+    /// `LIKE` pattern escaped with `\`. With `labels` these are the series with all of those labels,
+    /// otherwise only series without labels.
+    async fn find_series(&self, name: &str, labels: Option<&[(String, String)]>) -> anyhow::Result<Vec<(i64, String)>>;
+
+    /// Deletes the points of a series older than `time`, returning how many were deleted
+    async fn delete_before(&self, series_id: i64, time: NaiveDateTime) -> anyhow::Result<u64>;
+
+    /// Returns every series, or only `series` if given, with the number and time span of its points
+    async fn list_series_info(&self, series: Option<&str>) -> anyhow::Result<Vec<SeriesInfo>>;
+
+    /// Renames a series. Fails if a series named `to` already exists.
+    async fn rename_series(&self, from: &str, to: &str) -> anyhow::Result<()>;
+
+    /// Deletes the points of a series between `from` and `to`, returning how many were deleted. If
+    /// neither is given the series itself is deleted.
+    async fn delete_series(&self, series: &str, from: Option<NaiveDateTime>, to: Option<NaiveDateTime>) -> anyhow::Result<u64>;
+
+    /// Moves the points of series `from` into series `into` and deletes `from`, returning how many
+    /// points were moved
+    async fn merge_series(&self, from: &str, into: &str) -> anyhow::Result<u64>;
+
+    /// Returns how the values of a series are displayed, empty if nothing has been set
+    async fn get_metadata(&self, series: &str) -> anyhow::Result<SeriesMetadata>;
+
+    /// Replaces how the values of a series are displayed, creating the series if it doesn't exist
+    async fn set_metadata(&self, series: &str, metadata: &SeriesMetadata) -> anyhow::Result<()>;
+
+    /// Reclaims the space of deleted points
+    async fn vacuum(&self) -> anyhow::Result<()>;
+
+    /// Writes a consistent copy of the database to a new file at `path` while it stays in use
+    async fn backup(&self, path: &Path) -> anyhow::Result<()>;
+
+    /// Downsamples points before `until` into buckets of `resolution` seconds for long ranges
+    async fn roll_up(&self, resolution: i64, until: NaiveDateTime) -> anyhow::Result<()>;
+}
+
+pub(crate) struct SqliteStorage {
+    pool: sqlx::SqlitePool,
+    /// Ids of the series written to, by name
+    series_ids: Mutex<HashMap<String, i64>>,
+}
+
+impl SqliteStorage {
+    /// Opens the database in WAL mode, so that dashboards can be read while points are written
+    pub async fn connect(url: &str) -> anyhow::Result<Self> {
+        let options = SqliteConnectOptions::from_str(url)?
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal);
+        let pool = sqlx::sqlite::SqlitePool::connect_with(options).await?;
+        sqlx::migrate!("./migrations").run(&pool).await?;
+        db::label_series(&mut *pool.acquire().await?).await?;
+        Ok(Self {
+            pool,
+            series_ids: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub async fn check_integrity(&self) -> anyhow::Result<()> {
+        db::check_integrity(&mut *self.pool.acquire().await?).await
+    }
+
+    pub async fn close(self) {
+        self.pool.close().await
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn put(&self, series: &str, value: f64, time: Option<NaiveDateTime>) -> anyhow::Result<()> {
+        let time = time.unwrap_or(chrono::Utc::now().naive_utc());
+        self.put_all(vec![(series.to_string(), time, value)]).await
+    }
+
+    async fn put_all(&self, points: Vec<(String, NaiveDateTime, f64)>) -> anyhow::Result<()> {
+        let mut series_ids = self.series_ids.lock().await;
+        db::put_all(&mut *self.pool.acquire().await?, points, &mut series_ids).await
+    }
+
+    async fn get(&self, series: &str, range: TimeRange, buckets: i64) -> anyhow::Result<Vec<Bucket>> {
+        db::get(&mut *self.pool.acquire().await?, series, range, buckets).await
+    }
+
+    async fn get_last(&self, series: &str, until: Option<NaiveDateTime>) -> anyhow::Result<Option<Point>> {
+        db::get_last(&mut *self.pool.acquire().await?, series, until).await
+    }
+
+    async fn get_points(&self, series: &str, from: Option<NaiveDateTime>, to: Option<NaiveDateTime>) -> anyhow::Result<Vec<Point>> {
+        db::get_points(&mut *self.pool.acquire().await?, series, from, to).await
+    }
+
+    async fn list_series(&self) -> anyhow::Result<Vec<(i64, String)>> {
+        db::list_series(&mut *self.pool.acquire().await?).await
+    }
+
+    async fn find_series(&self, name: &str, labels: Option<&[(String, String)]>) -> anyhow::Result<Vec<(i64, String)>> {
+        db::find_series(&mut *self.pool.acquire().await?, name, labels).await
+    }
+
+    async fn delete_before(&self, series_id: i64, time: NaiveDateTime) -> anyhow::Result<u64> {
+        db::delete_before(&mut *self.pool.acquire().await?, series_id, time).await
+    }
+
+    async fn list_series_info(&self, series: Option<&str>) -> anyhow::Result<Vec<SeriesInfo>> {
+        db::list_series_info(&mut *self.pool.acquire().await?, series).await
+    }
+
+    async fn rename_series(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        db::rename_series(&mut *self.pool.acquire().await?, from, to).await
+    }
+
+    async fn delete_series(&self, series: &str, from: Option<NaiveDateTime>, to: Option<NaiveDateTime>) -> anyhow::Result<u64> {
+        db::delete_series(&mut *self.pool.acquire().await?, series, from, to).await
+    }
+
+    async fn merge_series(&self, from: &str, into: &str) -> anyhow::Result<u64> {
+        db::merge_series(&mut *self.pool.acquire().await?, from, into).await
+    }
+
+    async fn get_metadata(&self, series: &str) -> anyhow::Result<SeriesMetadata> {
+        db::get_metadata(&mut *self.pool.acquire().await?, series).await
+    }
+
+    async fn set_metadata(&self, series: &str, metadata: &SeriesMetadata) -> anyhow::Result<()> {
+        db::set_metadata(&mut *self.pool.acquire().await?, series, metadata).await
+    }
+
+    async fn vacuum(&self) -> anyhow::Result<()> {
+        db::vacuum(&mut *self.pool.acquire().await?).await
+    }
+
+    async fn backup(&self, path: &Path) -> anyhow::Result<()> {
+        db::backup(&mut *self.pool.acquire().await?, &path.to_string_lossy()).await
+    }
+
+    async fn roll_up(&self, resolution: i64, until: NaiveDateTime) -> anyhow::Result<()> {
+        db::roll_up(&mut *self.pool.acquire().await?, resolution, until).await
+    }
+}
@@ -1,4 +1,4 @@
-use std::f32::consts::PI;
+use std::f64::consts::PI;
 use std::fmt::Write;
 use askama::Template;
 use crate::model::*;
@@ -39,32 +39,58 @@ pub (crate) struct LabelWidgetTemplate{
 #[template(path = "widget_value.html")]
 pub (crate) struct ValueWidgetTemplate{
     // pub config: Widget,
-    pub point: Option<f32>,
+    /// The latest value of each matched series, with the legend that tells it apart
+    pub points: Vec<(String, Option<f64>)>,
+    pub metadata: SeriesMetadata,
     pub label: String,
     pub color: &'static str
 }
 
 impl ValueWidgetTemplate{
-    pub fn text(&self) -> String {
-        match self.point {
-            Some(value) => format!("{value:.2}"),
+    fn text(&self, point: Option<f64>) -> String {
+        match point {
+            Some(value) => self.metadata.format(value, 2),
             None => "N/A".into()
         }
     }
+
+    pub fn font_size(&self) -> f64 {
+        match self.points.len() {
+            0 | 1 => 32.0,
+            n => (90.0 / n as f64).min(12.0),
+        }
+    }
+
+    /// The lines of text with their vertical positions, one per series
+    pub fn rows(&self) -> Vec<(f64, String)> {
+        if self.points.len() < 2 {
+            let point = self.points.first().and_then(|(_, point)| *point);
+            return vec![(50.0, self.text(point))];
+        }
+        let spacing = self.font_size() * 1.2;
+        let top = 50.0 - spacing * (self.points.len() - 1) as f64 / 2.0;
+        self.points
+            .iter()
+            .enumerate()
+            .map(|(i, (legend, point))| (top + spacing * i as f64, format!("{legend}: {}", self.text(*point))))
+            .collect()
+    }
 }   
 
 #[derive(Template)]
 #[template(path = "widget_freshness.html")]
 pub (crate) struct FreshnessWidgetTemplate{
     // pub config: Widget,
-    pub last_update_time: Option<chrono::NaiveDateTime>
+    pub last_update_time: Option<chrono::NaiveDateTime>,
+    /// The end of the dashboard's range, which the age is measured to
+    pub now: chrono::NaiveDateTime,
 }
 
 impl FreshnessWidgetTemplate{
     pub(crate) fn freshness(&self) -> String{
         match self.last_update_time {
             Some(time) => {
-                let age = chrono::Utc::now().naive_utc() - time;
+                let age = self.now - time;
                 format!("{} mins", &age.num_minutes())
             },
             None => "N/A".into()
@@ -78,14 +104,27 @@ pub (crate) struct GaugeWidgetTemplate{
     // pub config: Widget,
     pub label: String,
     pub color: &'static str,
-    pub min: f32,
-    pub max: f32,
-    pub point: Option<f32>
+    pub min: f64,
+    pub max: f64,
+    pub point: Option<f64>,
+    /// How many series the selector matched
+    pub matched: usize,
+    pub metadata: SeriesMetadata,
 }
 
 impl GaugeWidgetTemplate{
+    pub fn text(&self) -> String {
+        match self.point {
+            Some(value) => self.metadata.format(value, 1),
+            None if self.matched > 1 => format!("{} series", self.matched),
+            None => "N/A".into()
+        }
+    }
+
     pub fn arc_svg(&self) -> String {
         match self.point {
+            // An empty range has no arc
+            Some(_) if self.min >= self.max => String::default(),
             Some(mut value) => {
                 value = value.clamp(self.min, self.max);
                 let proportion = (value - self.min) / (self.max - self.min);
@@ -112,11 +151,29 @@ impl GaugeWidgetTemplate{
     }
 }
 
+/// One series of a line chart
+pub (crate) struct LineSeries{
+    pub legend: String,
+    pub data: Vec<Point>,
+    /// The lower edge of the band when the line is drawn as an envelope, `data` being the upper edge
+    pub lower: Vec<Point>,
+}
+
+/// A line ready to draw
+pub (crate) struct LineSvg{
+    pub points: String,
+    pub is_envelope: bool,
+    pub legend: String,
+    pub legend_y: f64,
+    pub opacity: f64,
+}
+
 #[derive(Template)]
 #[template(path = "widget_line.html")]
 pub (crate) struct LineWidgetTemplate{
     // pub config: Widget,
-    pub data: Vec<Point>,
+    pub lines: Vec<LineSeries>,
+    pub metadata: SeriesMetadata,
     pub color: &'static str,
     pub label: String,
     pub width: u16,
@@ -124,32 +181,69 @@ pub (crate) struct LineWidgetTemplate{
 }
 
 impl LineWidgetTemplate{
+    fn all_points(&self) -> impl Iterator<Item = &Point> {
+        self.lines.iter().flat_map(|line| line.data.iter().chain(line.lower.iter()))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.lines.iter().all(|line| line.data.is_empty())
+    }
+
+    /// The range of the y axis, which covers every point and the expected range of the series
+    fn y_range(&self) -> Option<(f64, f64)> {
+        let values = || self.all_points().map(|point| point.value);
+        let y_min = values().chain(self.metadata.min).min_by(f64::total_cmp)?;
+        let y_max = values().chain(self.metadata.max).max_by(f64::total_cmp)?;
+        Some((y_min, y_max))
+    }
+
     pub fn axis_label_bottom(&self) -> String {
-        self
-            .data
-            .iter()
-            .map(|point| point.value)
-            .min_by(f32::total_cmp)
-            .map(|v| format!("{v:.1}"))
-            .unwrap_or_default()
+        match (self.is_empty(), self.y_range()) {
+            (false, Some((y_min, _))) => self.metadata.format(y_min, 1),
+            _ => String::default()
+        }
     }
 
     pub fn axis_label_top(&self) -> String {
-        self
-            .data
+        match (self.is_empty(), self.y_range()) {
+            (false, Some((_, y_max))) => self.metadata.format(y_max, 1),
+            _ => String::default()
+        }
+    }
+
+    /// The lines of every series. An envelope is outlined along its upper edge and back along its
+    /// lower edge. Later series are drawn fainter so overlapping lines can be told apart.
+    pub fn lines_svg(&self) -> Vec<LineSvg> {
+        let count = self.lines.len() as f64;
+        let (y_min, y_max) = self.y_range().unwrap_or_default();
+        self.lines
             .iter()
-            .map(|point| point.value)
-            .max_by(f32::total_cmp)
-            .map(|v| format!("{v:.1}"))
-            .unwrap_or_default()
+            .enumerate()
+            .map(|(i, line)| {
+                let is_envelope = !line.lower.is_empty();
+                let points = match is_envelope {
+                    true => self.to_svg(line.data.iter().chain(line.lower.iter().rev())),
+                    false => self.to_svg(line.data.iter()),
+                };
+                let legend_y = match (line.data.first(), y_max > y_min) {
+                    (Some(first), true) => self.normalize_y(first.value, y_min, y_max),
+                    _ => 0.0
+                };
+                LineSvg{ points, is_envelope, legend: line.legend.clone(), legend_y, opacity: 1.0 - 0.6 * i as f64 / count }
+            })
+            .collect()
+    }
+
+    fn normalize_y(&self, y: f64, y_min: f64, y_max: f64) -> f64 {
+        self.view_box_height() - (((y - y_min) / (y_max - y_min)) * self.view_box_height())
     }
 
-    pub fn points_svg(&self) -> String {
-        if self.data.is_empty() {
+    fn to_svg<'a>(&'a self, points: impl Iterator<Item = &'a Point>) -> String {
+        if self.is_empty() {
             return String::default();
         }
 
-        let xs = self.data.iter().map(|point| point.x()).collect::<Vec<_>>();
+        let xs = self.all_points().map(|point| point.x()).collect::<Vec<_>>();
         let x_min = xs.iter().cloned().min().unwrap();
         let x_max = xs.iter().cloned().max().unwrap();
 
@@ -157,32 +251,24 @@ impl LineWidgetTemplate{
             return String::default();
         }
 
-        let y_min = self.data.iter().map(|point| point.value).min_by(f32::total_cmp).unwrap();
-        let y_max = self.data.iter().map(|point| point.value).max_by(f32::total_cmp).unwrap();
+        let (y_min, y_max) = self.y_range().unwrap();
 
         if y_max == y_min {
             return String::default();
         }
 
         let view_box_width = self.view_box_width();
-        let view_box_height = self.view_box_height();
 
-        let x_range = (x_max - x_min) as f32;
+        let x_range = (x_max - x_min) as f64;
         let normalize_x = |x: i64| {
-            (((x - x_min) as f32) / x_range) * view_box_width
-        };
-
-        let normalize_y = |y: f32| {
-            view_box_height - (((y - y_min) / (y_max - y_min)) * view_box_height)
+            (((x - x_min) as f64) / x_range) * view_box_width
         };
 
-        let result: String = self
-            .data
-            .iter()
+        let result: String = points
             .map(|point|
                 (
                     normalize_x(point.x()),
-                    normalize_y(point.value)
+                    self.normalize_y(point.value, y_min, y_max)
                 )
             )
             .fold(String::new(), |mut s, (x, y)| { write!(s, "{x},{y} ").unwrap(); s});
@@ -190,19 +276,19 @@ impl LineWidgetTemplate{
         result
     }
 
-    pub fn view_box_width(&self) -> f32 {
-        self.width as f32 * 100.0
+    pub fn view_box_width(&self) -> f64 {
+        self.width as f64 * 100.0
     }
 
-    pub fn view_box_height(&self) -> f32 {
-        (self.height - 1) as f32 * 100.0
+    pub fn view_box_height(&self) -> f64 {
+        (self.height - 1) as f64 * 100.0
     }
 
-    pub fn y_axis_left(&self) -> f32 {
+    pub fn y_axis_left(&self) -> f64 {
         self.view_box_width() - 4.0
     }
 
-    pub fn y_axis_bottom(&self) -> f32 {
+    pub fn y_axis_bottom(&self) -> f64 {
         self.view_box_height()
     }
 }
@@ -212,5 +298,47 @@ impl LineWidgetTemplate{
 pub (crate) struct RangeWidgetTemplate{
     // pub config: Widget,
     pub range: u32,
-    pub label: String
+    pub label: String,
+    pub step: bool,
+    /// The range the dashboard is showing
+    pub current: TimeRange,
+}
+
+impl RangeWidgetTemplate{
+    fn query(seconds: u32, end: Option<chrono::NaiveDateTime>) -> String {
+        match end {
+            Some(end) => format!("?range={seconds}&to={}", end.and_utc().timestamp()),
+            None => format!("?range={seconds}"),
+        }
+    }
+
+    /// Shows this widget's range, ending where the current one does
+    pub fn link(&self) -> String {
+        Self::query(self.range, self.current.end)
+    }
+
+    /// Shows this widget's range up to now, to return from a range in the past
+    pub fn live_link(&self) -> String {
+        Self::query(self.range, None)
+    }
+
+    pub fn back_link(&self) -> String {
+        Self::query(self.current.seconds, Some(self.current.start()))
+    }
+
+    /// Moves forward by the current range, up to now
+    pub fn forward_link(&self) -> String {
+        let end = self.current.end() + chrono::TimeDelta::seconds(self.current.seconds as i64);
+        Self::query(self.current.seconds, Some(end).filter(|end| *end < chrono::Utc::now().naive_utc()))
+    }
+
+    pub fn is_live(&self) -> bool {
+        self.current.end.is_none()
+    }
+
+    /// The span of a range in the past, in UTC
+    pub fn window(&self) -> String {
+        let format = "%Y-%m-%d %H:%M";
+        format!("{} – {} UTC", self.current.start().format(format), self.current.end().format(format))
+    }
 }
\ No newline at end of file
@@ -0,0 +1,79 @@
+use chrono::NaiveDateTime;
+use crate::labels::validate_series;
+use crate::env::Webhook;
+use crate::model::json_to_number;
+
+type Points = Vec<(String, NaiveDateTime, f64)>;
+
+/// Reads the configured values from a webhook body. Returns the points which could be read and
+/// an error for each value which couldn't, by index of the value in the webhook config.
+pub(crate) fn to_points(
+    config: &Webhook,
+    body: &serde_json::Value,
+    parse_time: impl Fn(&str) -> Result<NaiveDateTime, String>,
+    now: NaiveDateTime,
+) -> Result<(Points, Vec<(usize, String)>), String> {
+    let time = match config.time.as_deref().map(|pointer| (pointer, body.pointer(pointer))) {
+        None => now,
+        Some((_, Some(serde_json::Value::Number(seconds)))) => parse_time(&seconds.to_string())?,
+        Some((_, Some(serde_json::Value::String(text)))) => parse_time(text)?,
+        Some((pointer, _)) => return Err(format!("Missing or invalid time field: {pointer}")),
+    };
+
+    let mut points = vec![];
+    let mut errors = vec![];
+    for (index, value) in config.values.iter().enumerate() {
+        let point = validate_series(&value.series)
+            .map_err(|e| format!("Invalid series name: {e}"))
+            .and_then(|series| {
+                let field = body.pointer(&value.field).ok_or(format!("Missing field: {}", value.field))?;
+                Ok((series, json_to_number(field).ok_or(format!("Not a number: {}", value.field))?))
+            });
+        match point {
+            Ok((series, number)) => points.push((series, time, number)),
+            Err(error) => errors.push((index, error)),
+        }
+    }
+
+    Ok((points, errors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+    use crate::env::WebhookValue;
+
+    #[test]
+    fn test_to_points() {
+        let now = DateTime::from_timestamp(1_718_292_600, 0).unwrap().naive_utc();
+        let then = DateTime::from_timestamp(1_718_289_000, 0).unwrap().naive_utc();
+        let parse_time = |text: &str| text.parse::<i64>()
+            .ok()
+            .and_then(|t| DateTime::from_timestamp(t, 0))
+            .map(|t| t.naive_utc())
+            .ok_or("Invalid time".to_string());
+
+        let mut config = Webhook{
+            name: "weather".to_string(),
+            token: "changeme".to_string(),
+            time: Some("/dt".to_string()),
+            values: vec![
+                WebhookValue{ field: "/main/temp".to_string(), series: "outside_temp_c".to_string() },
+                WebhookValue{ field: "/main/humidity".to_string(), series: "outside_humidity".to_string() },
+                WebhookValue{ field: "/wind/speed".to_string(), series: "wind_speed".to_string() },
+            ],
+        };
+        let body = serde_json::json!({ "dt": 1718289000, "main": { "temp": 12.5, "humidity": "80" }, "wind": {} });
+
+        let (points, errors) = to_points(&config, &body, parse_time, now).unwrap();
+        assert_eq!(points, vec![("outside_temp_c".to_string(), then, 12.5), ("outside_humidity".to_string(), then, 80.0)]);
+        assert_eq!(errors, vec![(2, "Missing field: /wind/speed".to_string())]);
+
+        assert!(to_points(&config, &serde_json::json!({ "main": { "temp": 12.5 } }), parse_time, now).is_err());
+
+        config.time = None;
+        let (points, _) = to_points(&config, &body, parse_time, now).unwrap();
+        assert_eq!(points[0].1, now);
+    }
+}